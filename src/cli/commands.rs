@@ -0,0 +1,229 @@
+use crate::config::path_functions::{reprocess, Mapping};
+use crate::config::types::MovieConfig;
+use crate::datastore::record::{ItemRecord, Status};
+use crate::datastore::store::{self, Forget};
+use crate::datastore::transfer;
+use crate::journal::client::{now, Journal, Selection};
+use crate::rss::client::{parse_item, req_to_rss};
+use anyhow::{bail, Context, Ok as AnyOk, Result as AnyResult};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// One shot commands run instead of the daemon when arguments are passed.
+#[derive(Debug)]
+pub enum Command {
+    Undo(Selection),
+    /// Imports an item from the quarantine folder using a hand written mapping.
+    Reprocess {
+        name: String,
+        mapping: Mapping,
+    },
+    /// Writes the whole history to a file, CSV if it ends in `.csv`, JSON otherwise.
+    Export(PathBuf),
+    /// Adds the items in an exported file that the history does not already have.
+    Import(PathBuf),
+    Search(String),
+    /// Like `Search`, with where each item has been since it was grabbed.
+    Show(String),
+    Forget(Forget),
+    /// Records everything currently in a feed as grabbed, without grabbing it.
+    MarkSeen(Option<String>),
+}
+
+impl Command {
+    /// Parses `std::env::args` minus the binary name. `None` means run the daemon.
+    ///
+    /// ```text
+    /// undo <id>
+    /// undo --from <unix secs> [--to <unix secs>]
+    /// undo --torrent <name>
+    /// reprocess <name> movie
+    /// reprocess <name> tv <show> <season>
+    /// history export <file>
+    /// history import <file>
+    /// history search <text>
+    /// history show <text>
+    /// history forget --link <link>
+    /// history forget --show <show>
+    /// history forget --from <unix secs> [--to <unix secs>]
+    /// history mark-seen [<feed url>]
+    /// ```
+    pub fn parse(args: &[String]) -> AnyResult<Option<Command>> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match args.as_slice() {
+            [] => AnyOk(None),
+            ["undo", "--torrent", name] => {
+                AnyOk(Some(Command::Undo(Selection::Torrent(name.to_string()))))
+            }
+            ["undo", "--from", from] => AnyOk(Some(Command::Undo(Selection::Range {
+                from: parse_number(from)?,
+                to: now(),
+            }))),
+            ["undo", "--from", from, "--to", to] => AnyOk(Some(Command::Undo(Selection::Range {
+                from: parse_number(from)?,
+                to: parse_number(to)?,
+            }))),
+            ["undo", id] => AnyOk(Some(Command::Undo(Selection::Id(parse_number(id)?)))),
+            ["reprocess", name, "movie"] => AnyOk(Some(Command::Reprocess {
+                name: name.to_string(),
+                mapping: Mapping::Movie,
+            })),
+            ["reprocess", name, "tv", show, season] => AnyOk(Some(Command::Reprocess {
+                name: name.to_string(),
+                mapping: Mapping::Episode {
+                    show: show.to_string(),
+                    season: season
+                        .parse::<i8>()
+                        .with_context(|| format!("expected a season number, got {}", season))?,
+                },
+            })),
+            ["history", "export", file] => AnyOk(Some(Command::Export(PathBuf::from(file)))),
+            ["history", "import", file] => AnyOk(Some(Command::Import(PathBuf::from(file)))),
+            ["history", "search", text] => AnyOk(Some(Command::Search(text.to_string()))),
+            ["history", "show", text] => AnyOk(Some(Command::Show(text.to_string()))),
+            ["history", "forget", "--link", link] => {
+                AnyOk(Some(Command::Forget(Forget::Link(link.to_string()))))
+            }
+            ["history", "forget", "--show", show] => {
+                AnyOk(Some(Command::Forget(Forget::Show(show.to_string()))))
+            }
+            ["history", "forget", "--from", from] => AnyOk(Some(Command::Forget(Forget::Range {
+                from: parse_number(from)?,
+                to: now(),
+            }))),
+            ["history", "forget", "--from", from, "--to", to] => {
+                AnyOk(Some(Command::Forget(Forget::Range {
+                    from: parse_number(from)?,
+                    to: parse_number(to)?,
+                })))
+            }
+            ["history", "mark-seen"] => AnyOk(Some(Command::MarkSeen(None))),
+            ["history", "mark-seen", feed] => {
+                AnyOk(Some(Command::MarkSeen(Some(feed.to_string()))))
+            }
+            _ => bail!("unknown command: {}", args.join(" ")),
+        }
+    }
+
+    pub async fn run(&self, config: Arc<MovieConfig>) -> AnyResult<()> {
+        match self {
+            Command::Undo(selection) => {
+                let journal = Journal::open(config.journal_path().to_path_buf())?;
+                let reverted = journal.undo(selection)?;
+                reverted.iter().for_each(|entry| {
+                    println!(
+                        "reverted {}: {:?} -> {:?}",
+                        entry.reverts.unwrap_or_default(),
+                        entry.source,
+                        entry.destination
+                    )
+                });
+                println!("{} entries reverted", reverted.len());
+                AnyOk(())
+            }
+            Command::Reprocess { name, mapping } => {
+                let journal = Journal::open(config.journal_path().to_path_buf())?;
+                let location = config
+                    .watch_paths()
+                    .iter()
+                    .map(|watch| config.quarantine_path(watch).join(name))
+                    .find(|location| location.exists())
+                    .with_context(|| format!("{} is not in quarantine", name))?;
+                let imported = reprocess(&location, mapping, &config, &journal)?;
+                println!("imported {:?} to {:?}", location, imported);
+                AnyOk(())
+            }
+            Command::Export(file) => {
+                let items = store::open_to_read(&config.history)?.items()?;
+                transfer::export(file, &items)?;
+                println!("exported {} items to {:?}", items.len(), file);
+                AnyOk(())
+            }
+            Command::Import(file) => {
+                let items = transfer::import(file)?;
+                let added = store::open(&config.history)?.import_items(&items)?;
+                println!(
+                    "imported {} of {} items from {:?}",
+                    added,
+                    items.len(),
+                    file
+                );
+                AnyOk(())
+            }
+            Command::Search(text) => {
+                let items = store::open_to_read(&config.history)?.search(text)?;
+                items.iter().for_each(|item| {
+                    println!(
+                        "{} {} {} {}",
+                        item.grabbed_at,
+                        item.status.as_str(),
+                        item.title.as_deref().unwrap_or("-"),
+                        item.link
+                    )
+                });
+                println!("{} items found", items.len());
+                AnyOk(())
+            }
+            Command::Show(text) => {
+                let store = store::open_to_read(&config.history)?;
+                let items = store.search(text)?;
+                for item in &items {
+                    println!("{} {}", item.title.as_deref().unwrap_or("-"), item.link);
+                    for event in store.events(&item.link)? {
+                        let mut line = format!("  {} {}", event.at, event.stage.as_str());
+                        if let Some(id) = event.torrent_id {
+                            line.push_str(&format!(" id {}", id));
+                        }
+                        if let Some(hash) = &event.hash {
+                            line.push_str(&format!(" hash {}", hash));
+                        }
+                        if let Some(path) = &event.path {
+                            line.push_str(&format!(" at {:?}", path));
+                        }
+                        println!("{}", line);
+                    }
+                }
+                println!("{} items found", items.len());
+                AnyOk(())
+            }
+            Command::Forget(forget) => {
+                let removed = store::open(&config.history)?.forget(forget)?;
+                println!("{} entries forgotten", removed);
+                AnyOk(())
+            }
+            Command::MarkSeen(feed) => {
+                let feed = feed.as_deref().unwrap_or(config.rss_feed());
+                let channel = req_to_rss(reqwest::get(feed).await?).await?;
+                let items: Vec<_> = channel
+                    .items()
+                    .iter()
+                    .filter_map(|item| parse_item(feed, item))
+                    .map(|record| ItemRecord {
+                        status: Status::Submitted,
+                        ..record
+                    })
+                    .collect();
+                let store = store::open(&config.history)?;
+                let added = store.import_items(&items)?;
+                // Items already known but never grabbed are marked too.
+                let confirmed: Vec<_> = items
+                    .iter()
+                    .map(|item| (item.clone(), Status::Submitted))
+                    .collect();
+                store.confirm_all(&confirmed)?;
+                println!(
+                    "marked {} of {} items in {} as seen",
+                    added,
+                    items.len(),
+                    feed
+                );
+                AnyOk(())
+            }
+        }
+    }
+}
+
+fn parse_number(raw: &str) -> AnyResult<u64> {
+    raw.parse::<u64>()
+        .with_context(|| format!("expected a number, got {}", raw))
+}
@@ -0,0 +1,22 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Replaces `file` without ever leaving it truncated: the new content goes to
+/// a temp file that is synced and then renamed over the old one.
+pub fn write_atomic(file: &Path, content: &[u8]) -> io::Result<()> {
+    let mut temp = file.to_path_buf().into_os_string();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut f = File::create(&temp)?;
+    f.write_all(content)?;
+    f.sync_all()?;
+    fs::rename(&temp, file)?;
+    // The rename itself is only durable once the directory is synced.
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
@@ -1,2 +1,7 @@
+pub mod atomic;
 pub mod path_functions;
+pub mod permissions;
+pub mod placement;
+pub mod raw_path;
+pub mod sanitise;
 pub mod types;
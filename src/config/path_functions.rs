@@ -1,14 +1,25 @@
-use anyhow::Result as AnyResult;
-use std::{fs, path::PathBuf, sync::Arc};
+use anyhow::{bail, Context, Result as AnyResult};
+use serde::{Deserialize, Serialize};
+use serde_json::to_vec_pretty;
+use std::{ffi::OsString, fs, path::Path, path::PathBuf};
 
 use regex::Regex;
 
-use super::types::MovieConfig;
+use super::permissions;
+use super::placement::choose_root;
+use super::sanitise::{ensure_within, sanitise_component, sanitise_os_component};
+use super::types::{ImportMode, MovieConfig, Permissions, WatchPath};
+use crate::journal::client::{now, Journal};
+use crate::nfo::client as nfo;
+use crate::rss::metadata::MetadataLog;
 
+/// Name parsing works on a lossy decode of the file name, so names that are
+/// not valid UTF-8 can still be routed. Only `get_episode` keeps the raw bytes.
 trait TransmissionFilters {
     fn is_movie(&self) -> bool;
     fn get_season(&self) -> Option<i8>;
-    fn get_episode(&self) -> Option<String>;
+    fn get_episode(&self) -> Option<OsString>;
+    fn get_episode_number(&self) -> Option<u16>;
     fn get_show_name(&self) -> Option<String>;
 }
 
@@ -17,10 +28,7 @@ impl TransmissionFilters for PathBuf {
         let pattern = Regex::new(r"[sS](\d{2})").unwrap();
         match self.file_name() {
             None => false,
-            Some(filename) => filename
-                .to_str()
-                .map(|t| !pattern.is_match(t))
-                .unwrap_or(true),
+            Some(filename) => !pattern.is_match(&filename.to_string_lossy()),
         }
     }
 
@@ -29,14 +37,19 @@ impl TransmissionFilters for PathBuf {
         match self.file_name() {
             None => None,
             Some(filename) => {
-                let season_start = pattern.captures(filename.to_str()?)?.get(1)?.as_str();
+                let filename = filename.to_string_lossy();
+                let season_start = pattern.captures(&filename)?.get(1)?.as_str();
                 season_start.parse::<i8>().ok()
             }
         }
     }
 
-    fn get_episode(&self) -> Option<String> {
-        Some(self.file_name()?.to_str()?.to_string())
+    fn get_episode(&self) -> Option<OsString> {
+        Some(self.file_name()?.to_os_string())
+    }
+
+    fn get_episode_number(&self) -> Option<u16> {
+        episode_number(self)
     }
 
     fn get_show_name(&self) -> Option<String> {
@@ -48,70 +61,544 @@ impl TransmissionFilters for PathBuf {
             None => None,
             Some(filename) => {
                 let site_filter = Regex::new(r"[wW]{3}[\s\.]?\w+[\s\.]\w{3}").unwrap();
-                let season_start = pattern.find(filename.to_str()?)?.start();
-                let possible = &filename.to_str().unwrap_or("")[0..season_start - 1];
+                let filename = filename.to_string_lossy();
+                // `start` is always a char boundary, unlike the byte before it.
+                let season_start = pattern.find(&filename)?.start();
+                let possible = filename[..season_start].trim_end_matches(['.', ' ', '-', '_']);
+                if possible.is_empty() {
+                    return None;
+                }
                 Some(site_filter.replace(possible, "").to_string().to_lowercase())
             }
         }
     }
 }
 
-pub fn generate_target_path(path_buf: &PathBuf, movie_config: Arc<MovieConfig>) -> Option<PathBuf> {
-    let movie = movie_config.movie_dir.to_owned()?;
-    let tv = movie_config.tv_dir.to_owned()?;
-
-    if path_buf.is_movie() {
-        Some(PathBuf::from(format!(
-            "{}/{}",
-            movie,
-            path_buf.get_episode()?
-        )))
-    } else if path_buf.is_dir() {
-        Some(PathBuf::from(format!(
-            "{}/{}/{:02}",
-            tv,
-            path_buf.get_show_name()?,
-            path_buf.get_season()?,
-        )))
+/// The episode number after a season marker, e.g. 3 in `S01E03`.
+pub fn episode_number(path: &Path) -> Option<u16> {
+    let pattern = Regex::new(r"[sS]\d{2}[eE](\d{2,3})").unwrap();
+    let filename = path.file_name()?.to_string_lossy();
+    pattern.captures(&filename)?.get(1)?.as_str().parse().ok()
+}
+
+/// What the name heuristics made of a download, for hooks and metadata files.
+#[derive(Serialize, Debug, Clone)]
+pub struct ReleaseInfo {
+    pub movie: bool,
+    pub show: Option<String>,
+    pub season: Option<i8>,
+    pub episode: Option<u16>,
+    pub name: String,
+}
+
+pub fn release_info(path_buf: &PathBuf) -> ReleaseInfo {
+    ReleaseInfo {
+        movie: path_buf.is_movie(),
+        show: path_buf.get_show_name(),
+        season: path_buf.get_season(),
+        episode: path_buf.get_episode_number(),
+        name: path_buf
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// `release_info` for an RSS title. Titles are not paths, so separators are
+/// read as spaces rather than cutting the name short. `None` if nothing is
+/// left to parse.
+pub fn title_info(title: &str) -> Option<ReleaseInfo> {
+    let name = title.replace(['/', '\\'], " ");
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some(release_info(&PathBuf::from(name)))
+}
+
+/// A target chosen by hand for something the name heuristics could not parse.
+#[derive(Debug, Clone)]
+pub enum Mapping {
+    Movie,
+    Episode { show: String, season: i8 },
+}
+
+impl Mapping {
+    /// `release_info` with the guessed parts replaced by what was chosen by hand.
+    fn release_info(&self, path_buf: &PathBuf) -> ReleaseInfo {
+        let info = release_info(path_buf);
+        match self {
+            Mapping::Movie => ReleaseInfo {
+                movie: true,
+                show: None,
+                season: None,
+                episode: None,
+                ..info
+            },
+            Mapping::Episode { show, season } => ReleaseInfo {
+                movie: false,
+                show: Some(show.clone()),
+                season: Some(*season),
+                ..info
+            },
+        }
+    }
+}
+
+/// NFO files for an import, if enabled, using whatever the feed said about it.
+fn write_nfos(
+    location: &Path,
+    info: &ReleaseInfo,
+    config: &MovieConfig,
+    watch: &WatchPath,
+) -> Vec<String> {
+    if !config.nfo.enabled {
+        return vec![];
+    }
+    let item = MetadataLog::new(&config.nfo).find(&info.name);
+    nfo::write(location, info, item.as_ref(), &watch.permissions)
+}
+
+pub fn generate_target_path(path_buf: &PathBuf, watch: &WatchPath) -> AnyResult<PathBuf> {
+    let mapping = if path_buf.is_movie() {
+        Mapping::Movie
     } else {
-        Some(PathBuf::from(format!(
-            "{}/{}/{:02}/{}",
-            tv,
-            path_buf.get_show_name()?,
-            path_buf.get_season()?,
-            path_buf.get_episode()?,
-        )))
-    }
-}
-
-fn copy_directory(src: &PathBuf, dst: PathBuf) -> AnyResult<()> {
-    fs::create_dir_all(&dst)?;
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let ty = entry.file_type()?;
-        if ty.is_dir() {
-            copy_directory(&entry.path(), dst.join(entry.file_name()))?;
+        Mapping::Episode {
+            show: path_buf
+                .get_show_name()
+                .context("could not find a show name before the season marker")?,
+            season: path_buf
+                .get_season()
+                .context("could not parse a season number")?,
+        }
+    };
+    mapped_target_path(path_buf, watch, &mapping)
+}
+
+pub fn mapped_target_path(
+    path_buf: &PathBuf,
+    watch: &WatchPath,
+    mapping: &Mapping,
+) -> AnyResult<PathBuf> {
+    let name = sanitise_os_component(
+        &path_buf
+            .get_episode()
+            .context("download has no file name")?,
+    );
+    match mapping {
+        Mapping::Movie => {
+            let movie = watch.movie_dir.as_ref().context("no movieDir configured")?;
+            Ok(PathBuf::from(movie).join(name))
+        }
+        Mapping::Episode { show, season } => {
+            let tv = watch.tv_dir.as_ref().context("no tvDir configured")?;
+            let season_dir = PathBuf::from(tv)
+                .join(sanitise_component(show))
+                .join(format!("{:02}", season));
+            if path_buf.is_dir() {
+                Ok(season_dir)
+            } else {
+                Ok(season_dir.join(name))
+            }
+        }
+    }
+}
+
+/// Places files for one import and collects anything that went wrong without
+/// being fatal, such as a failed chown.
+struct Importer<'a> {
+    mode: ImportMode,
+    permissions: &'a Permissions,
+    journal: &'a Journal,
+    warnings: Vec<String>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(mode: ImportMode, permissions: &'a Permissions, journal: &'a Journal) -> Self {
+        Self {
+            mode,
+            permissions,
+            journal,
+            warnings: vec![],
+        }
+    }
+
+    /// Like `fs::create_dir_all`, but applies permissions to each directory it creates.
+    fn create_dirs(&mut self, dir: &Path) -> AnyResult<()> {
+        if dir.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = dir.parent() {
+            self.create_dirs(parent)?;
+        }
+        fs::create_dir(dir)?;
+        self.warnings
+            .extend(permissions::apply(dir, self.permissions, true));
+        Ok(())
+    }
+
+    fn place_file(&mut self, src: &Path, dst: &Path, torrent: &str) -> AnyResult<()> {
+        match self.mode {
+            ImportMode::Move | ImportMode::Copy => {
+                fs::copy(src, dst)?;
+            }
+            ImportMode::Hardlink => {
+                if let Err(e) = fs::hard_link(src, dst) {
+                    println!("could not hardlink {:?} ({:?}), copying instead", src, e);
+                    fs::copy(src, dst)?;
+                }
+            }
+        }
+        self.warnings
+            .extend(permissions::apply(dst, self.permissions, false));
+        self.journal
+            .record(torrent, src, dst, self.mode.into(), None)?;
+        Ok(())
+    }
+
+    fn copy_directory(&mut self, src: &PathBuf, dst: PathBuf, torrent: &str) -> AnyResult<()> {
+        self.create_dirs(&dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            let ty = entry.file_type()?;
+            let name = sanitise_os_component(&entry.file_name());
+            if ty.is_dir() {
+                self.copy_directory(&entry.path(), dst.join(name), torrent)?;
+            } else {
+                self.place_file(&entry.path(), &dst.join(name), torrent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Places `path` at `final_location`, which must lie inside `root`.
+    fn import_to(
+        &mut self,
+        path: &PathBuf,
+        root: &Path,
+        final_location: PathBuf,
+    ) -> AnyResult<PathBuf> {
+        ensure_within(root, &final_location)?;
+        let target_dir = final_location
+            .parent()
+            .context("target has no parent directory")?;
+        let torrent = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.create_dirs(target_dir)?;
+        if path.is_dir() {
+            self.copy_directory(path, final_location.clone(), &torrent)?;
+            if self.mode == ImportMode::Move {
+                fs::remove_dir_all(path).unwrap_or_default();
+            }
         } else {
-            fs::copy(entry.path(), dst.join(entry.file_name()))?;
+            self.place_file(path, &final_location, &torrent)?;
+            if self.mode == ImportMode::Move {
+                fs::remove_file(path).unwrap_or_default()
+            }
         }
+        self.warnings
+            .iter()
+            .for_each(|warning| println!("warning: {}", warning));
+        Ok(final_location)
     }
-    Ok(())
 }
 
-pub fn copy_file(path: &PathBuf, config: Arc<MovieConfig>) -> AnyResult<()> {
-    let save_location = config.save_path();
-    let target = generate_target_path(path, Arc::clone(&config));
-    let final_location = save_location.join(target.unwrap());
-    let target_dir = final_location.parent().unwrap();
-    if !target_dir.exists() {
-        fs::create_dir_all(target_dir)?;
+/// What `copy_file` did with a download.
+#[derive(Debug)]
+pub enum Outcome {
+    /// `warnings` lists what could not be applied, e.g. ownership, without failing the import.
+    Imported {
+        location: PathBuf,
+        warnings: Vec<String>,
+    },
+    /// Moved aside because no library location could be worked out.
+    Quarantined { location: PathBuf, reason: String },
+}
+
+/// Written next to a quarantined item so it can be reprocessed later.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuarantineReason {
+    pub reason: String,
+    #[serde(with = "super::raw_path")]
+    pub source: PathBuf,
+    #[serde(with = "super::raw_path")]
+    pub watch_path: PathBuf,
+    pub timestamp: u64,
+}
+
+pub fn reason_file(location: &Path) -> PathBuf {
+    let mut name = location.file_name().unwrap_or_default().to_os_string();
+    name.push(".reason.json");
+    location.with_file_name(name)
+}
+
+fn quarantine(
+    path: &PathBuf,
+    watch: &WatchPath,
+    config: &MovieConfig,
+    reason: String,
+    journal: &Journal,
+) -> AnyResult<Outcome> {
+    let name = path.file_name().context("download has no file name")?;
+    let root = config.quarantine_path(watch);
+    let location = root.join(sanitise_os_component(name));
+    choose_root(
+        path,
+        Path::new(name),
+        watch,
+        std::slice::from_ref(&root),
+        config.free_space_margin(),
+    )?;
+    if location.exists() {
+        bail!("{:?} is already in quarantine", location)
     }
-    if path.is_dir() {
-        copy_directory(path, final_location)?;
-        fs::remove_dir_all(path).unwrap_or_default();
-    } else {
-        fs::copy(path, final_location)?;
-        fs::remove_file(path).unwrap_or_default()
+    let location =
+        Importer::new(watch.mode, &watch.permissions, journal).import_to(path, &root, location)?;
+    let note = QuarantineReason {
+        reason: reason.clone(),
+        source: path.clone(),
+        watch_path: watch.path.clone(),
+        timestamp: now(),
+    };
+    fs::write(reason_file(&location), to_vec_pretty(&note)?)?;
+    Ok(Outcome::Quarantined { location, reason })
+}
+
+/// Imports a download into the library, or into quarantine if its name
+/// cannot be parsed into a library location.
+pub fn copy_file(
+    path: &PathBuf,
+    watch: &WatchPath,
+    config: &MovieConfig,
+    journal: &Journal,
+) -> AnyResult<Outcome> {
+    match generate_target_path(path, watch) {
+        Ok(target) => {
+            let root = choose_root(
+                path,
+                &target,
+                watch,
+                &watch.library_roots,
+                config.free_space_margin(),
+            )?;
+            let mut importer = Importer::new(watch.mode, &watch.permissions, journal);
+            let location = importer.import_to(path, &root, root.join(target))?;
+            let nfo_warnings = write_nfos(&location, &release_info(path), config, watch);
+            nfo_warnings
+                .iter()
+                .for_each(|warning| println!("warning: {}", warning));
+            importer.warnings.extend(nfo_warnings);
+            Ok(Outcome::Imported {
+                location,
+                warnings: importer.warnings,
+            })
+        }
+        Err(reason) => quarantine(path, watch, config, format!("{:#}", reason), journal),
+    }
+}
+
+/// Imports a quarantined item using a hand written mapping and clears its reason file.
+pub fn reprocess(
+    location: &PathBuf,
+    mapping: &Mapping,
+    config: &MovieConfig,
+    journal: &Journal,
+) -> AnyResult<PathBuf> {
+    let note: QuarantineReason = serde_json::from_slice(&fs::read(reason_file(location))?)?;
+    let watch = config
+        .watch_paths()
+        .iter()
+        .find(|watch| watch.path == note.watch_path)
+        .or(config.watch_paths().first())
+        .context("no watch paths configured")?;
+    let target = mapped_target_path(location, watch, mapping)?;
+    let root = choose_root(
+        location,
+        &target,
+        watch,
+        &watch.library_roots,
+        config.free_space_margin(),
+    )?;
+    let imported = Importer::new(ImportMode::Move, &watch.permissions, journal).import_to(
+        location,
+        &root,
+        root.join(target),
+    )?;
+    fs::remove_file(reason_file(location))?;
+    write_nfos(&imported, &mapping.release_info(location), config, watch)
+        .iter()
+        .for_each(|warning| println!("warning: {}", warning));
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, ScratchDir};
+    use serde_json::json;
+
+    /// A config watching `dir/done` with the library in `dir/library`.
+    fn setup(dir: &ScratchDir, fields: serde_json::Value) -> (MovieConfig, Journal) {
+        fs::create_dir_all(dir.join("done")).unwrap();
+        let mut raw = json!({
+            "watch": dir.join("done"),
+            "saveDir": dir.join("library"),
+            "freeSpaceMarginMb": 0,
+        });
+        raw.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        let journal = Journal::open(dir.join("journal")).unwrap();
+        (config(raw), journal)
+    }
+
+    fn show(name: &str) -> Option<String> {
+        PathBuf::from(name).get_show_name()
+    }
+
+    #[test]
+    fn show_names_stop_before_the_season_marker() {
+        assert_eq!(show("The.Show.S01E02.mkv").as_deref(), Some("the.show"));
+        assert_eq!(show("The Show - S01E02.mkv").as_deref(), Some("the show"));
+        assert_eq!(show("The_Show_S01E02.mkv").as_deref(), Some("the_show"));
+        assert_eq!(show("S01E02.mkv"), None);
+        assert_eq!(show(". S01E02.mkv"), None);
+        assert_eq!(show("Some.Movie.2020.mkv"), None);
+    }
+
+    #[test]
+    fn non_ascii_show_names_are_not_split_inside_a_character() {
+        assert_eq!(
+            show("Caf\u{e9}\u{2013}S01E02.mkv").as_deref(),
+            Some("caf\u{e9}\u{2013}")
+        );
+        assert_eq!(
+            show("Caf\u{e9} \u{2013} S01E02.mkv").as_deref(),
+            Some("caf\u{e9} \u{2013}")
+        );
+        assert_eq!(
+            show("\u{9032}\u{6483}\u{306e}\u{5de8}\u{4eba}S04E01.mkv").as_deref(),
+            Some("\u{9032}\u{6483}\u{306e}\u{5de8}\u{4eba}")
+        );
+        let info = release_info(&PathBuf::from("\u{5de8}\u{4eba}.S04E01.mkv"));
+        assert_eq!(info.season, Some(4));
+        assert_eq!(info.episode, Some(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_parsed_from_a_lossy_decode() {
+        use std::os::unix::ffi::OsStrExt;
+        let name = |bytes: &[u8]| PathBuf::from(std::ffi::OsStr::from_bytes(bytes));
+        assert_eq!(
+            name(b"Caf\xe9S01E02.mkv").get_show_name().as_deref(),
+            Some("caf\u{fffd}")
+        );
+        assert_eq!(
+            name(b"Caf\xe9.S01E02.mkv").get_show_name().as_deref(),
+            Some("caf\u{fffd}")
+        );
+        assert_eq!(name(b"\xff\xfe.S01E02.mkv").get_season(), Some(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_downloads_keep_their_bytes_in_the_library() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = ScratchDir::new("copy-file-non-utf8");
+        let (config, journal) = setup(&dir, json!({}));
+        let watch = &config.watch_paths()[0];
+        let name = std::ffi::OsStr::from_bytes(b"Caf\xe9.S01E02.mkv");
+        let source = dir.join("done").join(name);
+        fs::write(&source, "episode").unwrap();
+
+        let Outcome::Imported { location, .. } =
+            copy_file(&source, watch, &config, &journal).unwrap()
+        else {
+            panic!("episode was not imported")
+        };
+        assert_eq!(location, dir.join("library/tv/caf\u{fffd}/01").join(name));
+        assert!(location.is_file());
+    }
+
+    #[test]
+    fn episodes_and_movies_are_imported_into_the_library() {
+        let dir = ScratchDir::new("copy-file");
+        let (config, journal) = setup(&dir, json!({}));
+        let watch = &config.watch_paths()[0];
+        let episode = dir.join("done/The.Show.S02E03.720p.mkv");
+        let movie = dir.join("done/Some.Movie.2020.mkv");
+        fs::write(&episode, "episode").unwrap();
+        fs::write(&movie, "movie").unwrap();
+
+        let Outcome::Imported { location, .. } =
+            copy_file(&episode, watch, &config, &journal).unwrap()
+        else {
+            panic!("episode was not imported")
+        };
+        assert_eq!(
+            location,
+            dir.join("library/tv/the.show/02/The.Show.S02E03.720p.mkv")
+        );
+        assert!(location.is_file());
+        assert!(!episode.exists());
+
+        let Outcome::Imported { location, .. } =
+            copy_file(&movie, watch, &config, &journal).unwrap()
+        else {
+            panic!("movie was not imported")
+        };
+        assert_eq!(location, dir.join("library/movie/Some.Movie.2020.mkv"));
+    }
+
+    #[test]
+    fn unparseable_names_are_quarantined_with_a_reason() {
+        let dir = ScratchDir::new("copy-file-quarantine");
+        let (config, journal) = setup(&dir, json!({}));
+        let watch = &config.watch_paths()[0];
+        let source = dir.join("done/S01E01.mkv");
+        fs::write(&source, "episode").unwrap();
+
+        let Outcome::Quarantined { location, reason } =
+            copy_file(&source, watch, &config, &journal).unwrap()
+        else {
+            panic!("expected quarantine")
+        };
+        assert_eq!(location, dir.join("library/quarantine/S01E01.mkv"));
+        assert!(location.is_file());
+        assert!(!source.exists());
+        assert!(reason.contains("show name"), "{}", reason);
+        let note: QuarantineReason =
+            serde_json::from_slice(&fs::read(reason_file(&location)).unwrap()).unwrap();
+        assert_eq!(note.reason, reason);
+        assert_eq!(note.source, source);
+        assert_eq!(note.watch_path, watch.path);
+
+        let mapping = Mapping::Episode {
+            show: "Pilot".to_string(),
+            season: 1,
+        };
+        let imported = reprocess(&location, &mapping, &config, &journal).unwrap();
+        assert_eq!(imported, dir.join("library/tv/Pilot/01/S01E01.mkv"));
+        assert!(imported.is_file());
+        assert!(!reason_file(&location).exists());
+    }
+
+    #[test]
+    fn a_movie_without_a_movie_dir_is_quarantined() {
+        let dir = ScratchDir::new("copy-file-no-movie-dir");
+        let (config, journal) = setup(&dir, json!({ "movieDir": null }));
+        let watch = &config.watch_paths()[0];
+        let source = dir.join("done/Some.Movie.2020.mkv");
+        fs::write(&source, "movie").unwrap();
+
+        let outcome = copy_file(&source, watch, &config, &journal).unwrap();
+        assert!(
+            matches!(&outcome, Outcome::Quarantined { reason, .. } if reason.contains("movieDir")),
+            "{:?}",
+            outcome
+        );
     }
-    Ok(())
 }
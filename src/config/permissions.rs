@@ -0,0 +1,128 @@
+use super::types::Permissions;
+use std::fs;
+use std::path::Path;
+
+/// Applies owner and mode to something the mover just created. Problems are
+/// returned as messages rather than errors so the import itself carries on.
+#[cfg(unix)]
+pub fn apply(path: &Path, permissions: &Permissions, is_dir: bool) -> Vec<String> {
+    use std::os::unix::fs::{chown, PermissionsExt};
+
+    let mut problems = vec![];
+    if permissions.is_empty() {
+        return problems;
+    }
+    if permissions.uid.is_some() || permissions.gid.is_some() {
+        if let Err(e) = chown(path, permissions.uid, permissions.gid) {
+            problems.push(format!("could not chown {:?}: {}", path, e));
+        }
+    }
+    let mode = if is_dir {
+        match (permissions.dir_mode, permissions.setgid) {
+            (Some(mode), true) => Some(mode | 0o2000),
+            (Some(mode), false) => Some(mode),
+            (None, true) => fs::metadata(path)
+                .map(|meta| meta.permissions().mode() | 0o2000)
+                .ok(),
+            (None, false) => None,
+        }
+    } else {
+        permissions.file_mode
+    };
+    if let Some(mode) = mode {
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+            problems.push(format!("could not chmod {:?} to {:o}: {}", path, mode, e));
+        }
+    }
+    problems
+}
+
+#[cfg(not(unix))]
+pub fn apply(path: &Path, permissions: &Permissions, _is_dir: bool) -> Vec<String> {
+    if permissions.is_empty() {
+        vec![]
+    } else {
+        vec![format!(
+            "ownership and modes are not supported here, left {:?} as is",
+            path
+        )]
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+    use serde_json::json;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    fn permissions(fields: serde_json::Value) -> Permissions {
+        serde_json::from_value(fields).unwrap()
+    }
+
+    #[test]
+    fn modes_are_read_as_octal() {
+        let parsed = permissions(json!({ "fileMode": "0640", "dirMode": "0o750" }));
+        assert_eq!(parsed.file_mode, Some(0o640));
+        assert_eq!(parsed.dir_mode, Some(0o750));
+        assert_eq!(
+            permissions(json!({ "fileMode": 420 })).file_mode,
+            Some(0o644)
+        );
+        assert!(serde_json::from_value::<Permissions>(json!({ "fileMode": "0689" })).is_err());
+    }
+
+    #[test]
+    fn files_and_directories_get_their_own_mode() {
+        let dir = ScratchDir::new("permissions");
+        let file = dir.join("e01.mkv");
+        let season = dir.join("01");
+        fs::write(&file, "video").unwrap();
+        fs::create_dir(&season).unwrap();
+        let wanted = permissions(json!({ "fileMode": "0640", "dirMode": "0750", "setgid": true }));
+
+        assert!(apply(&file, &wanted, false).is_empty());
+        assert!(apply(&season, &wanted, true).is_empty());
+        assert_eq!(mode(&file), 0o640);
+        assert_eq!(mode(&season), 0o2750);
+    }
+
+    #[test]
+    fn setgid_alone_keeps_the_directory_mode() {
+        let dir = ScratchDir::new("permissions-setgid");
+        let season = dir.join("01");
+        fs::create_dir(&season).unwrap();
+        fs::set_permissions(&season, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert!(apply(&season, &permissions(json!({ "setgid": true })), true).is_empty());
+        assert_eq!(mode(&season), 0o2755);
+    }
+
+    #[test]
+    fn nothing_configured_changes_nothing() {
+        let dir = ScratchDir::new("permissions-empty");
+        let file = dir.join("e01.mkv");
+        fs::write(&file, "video").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o604)).unwrap();
+
+        assert!(apply(&file, &Permissions::default(), false).is_empty());
+        assert_eq!(mode(&file), 0o604);
+    }
+
+    #[test]
+    fn failures_are_reported_instead_of_raised() {
+        let dir = ScratchDir::new("permissions-missing");
+        let owner = fs::metadata(dir.path()).unwrap();
+        let wanted =
+            permissions(json!({ "uid": owner.uid(), "gid": owner.gid(), "fileMode": "0640" }));
+
+        let problems = apply(&dir.join("gone.mkv"), &wanted, false);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("could not chown"));
+        assert!(problems[1].starts_with("could not chmod"));
+    }
+}
@@ -0,0 +1,209 @@
+use super::types::{ImportMode, Placement, WatchPath};
+use anyhow::{bail, Context, Result as AnyResult};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Nearest ancestor of `path` that exists, since targets usually do not yet.
+fn existing_ancestor(path: &Path) -> Option<&Path> {
+    path.ancestors().find(|ancestor| ancestor.exists())
+}
+
+/// Bytes an unprivileged user can still write on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> AnyResult<u64> {
+    use nix::sys::statvfs::statvfs;
+    let existing = existing_ancestor(path).context("no part of the path exists")?;
+    let stats = statvfs(existing).with_context(|| format!("could not statvfs {:?}", existing))?;
+    Ok(stats.blocks_available() as u64 * stats.fragment_size() as u64)
+}
+
+/// Free space cannot be queried here, so every root is taken to have room.
+#[cfg(not(unix))]
+pub fn available_space(path: &Path) -> AnyResult<u64> {
+    existing_ancestor(path).context("no part of the path exists")?;
+    Ok(u64::MAX)
+}
+
+#[cfg(unix)]
+fn same_device(a: &Path, b: &Path) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Some(fs::metadata(a).ok()?.dev() == fs::metadata(b).ok()?.dev())
+}
+
+#[cfg(not(unix))]
+fn same_device(_a: &Path, _b: &Path) -> Option<bool> {
+    None
+}
+
+/// Total size of a file, or of every file below a directory.
+pub fn import_size(path: &Path) -> io::Result<u64> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += import_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Hardlinks on the same filesystem take no extra space.
+fn needed_space(source: &Path, root: &Path, mode: ImportMode, size: u64) -> u64 {
+    let same_device = || same_device(source, existing_ancestor(root)?);
+    if mode == ImportMode::Hardlink && same_device().unwrap_or(false) {
+        0
+    } else {
+        size
+    }
+}
+
+/// The show folder an episode `target` goes into, i.e. the tv dir, however
+/// many components it has, plus the next one.
+fn show_dir(target: &Path, watch: &WatchPath) -> Option<PathBuf> {
+    let tv = Path::new(watch.tv_dir.as_ref()?);
+    let mut rest = target.strip_prefix(tv).ok()?.components();
+    let show = rest.next()?;
+    rest.next()?;
+    Some(tv.join(show))
+}
+
+/// Picks the library root to import `source` into, at `target` relative to
+/// that root. Roots without room for the import plus `margin` bytes are never
+/// chosen, so a copy cannot run a disk full halfway through.
+pub fn choose_root(
+    source: &Path,
+    target: &Path,
+    watch: &WatchPath,
+    roots: &[PathBuf],
+    margin: u64,
+) -> AnyResult<PathBuf> {
+    let size = import_size(source)?;
+    let mut candidates = vec![];
+    for root in roots {
+        match available_space(root) {
+            Ok(free) => candidates.push((root, free, needed_space(source, root, watch.mode, size))),
+            Err(e) => println!("skipping library root {:?}: {:?}", root, e),
+        }
+    }
+    let fitting: Vec<&(&PathBuf, u64, u64)> = candidates
+        .iter()
+        .filter(|(_, free, needed)| free.saturating_sub(*needed) >= margin)
+        .collect();
+
+    if let (Placement::ExistingShow, Some(show_dir)) = (watch.placement, show_dir(target, watch)) {
+        let existing = candidates
+            .iter()
+            .find(|(root, _, _)| root.join(&show_dir).is_dir());
+        match existing {
+            Some(existing) if fitting.contains(&existing) => return Ok(existing.0.clone()),
+            Some((root, _, _)) => println!(
+                "{:?} already holds {:?} but is too full, placing elsewhere",
+                root, show_dir
+            ),
+            None => (),
+        }
+    }
+
+    match fitting.iter().max_by_key(|(_, free, _)| *free) {
+        Some((root, _, _)) => Ok((*root).clone()),
+        None => {
+            let best = candidates
+                .iter()
+                .map(|(_, free, _)| *free)
+                .max()
+                .unwrap_or(0);
+            bail!(
+                "not enough free space for {:?}: need {} bytes plus a {} byte margin, the emptiest root has {}",
+                source,
+                size,
+                margin,
+                best
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, ScratchDir};
+    use serde_json::json;
+
+    #[test]
+    fn import_size_adds_up_everything_below_a_directory() {
+        let dir = ScratchDir::new("placement-size");
+        fs::create_dir_all(dir.join("Show.S01/extras")).unwrap();
+        fs::write(dir.join("Show.S01/e01.mkv"), [0; 10]).unwrap();
+        fs::write(dir.join("Show.S01/extras/e02.mkv"), [0; 5]).unwrap();
+        assert_eq!(import_size(&dir.join("Show.S01")).unwrap(), 15);
+        assert_eq!(import_size(&dir.join("Show.S01/e01.mkv")).unwrap(), 10);
+    }
+
+    #[test]
+    fn show_dirs_sit_right_below_the_tv_dir() {
+        let config = config(json!({ "tvDir": "media/tv" }));
+        let watch = &config.watch_paths()[0];
+        assert_eq!(
+            show_dir(Path::new("media/tv/the.show/01/e01.mkv"), watch),
+            Some(PathBuf::from("media/tv/the.show"))
+        );
+        assert_eq!(
+            show_dir(Path::new("media/tv/the.show/01"), watch),
+            Some(PathBuf::from("media/tv/the.show"))
+        );
+        assert_eq!(show_dir(Path::new("media/tv/the.show"), watch), None);
+        assert_eq!(show_dir(Path::new("movie/Some.Movie.mkv"), watch), None);
+    }
+
+    #[test]
+    fn episodes_follow_the_root_that_already_has_the_show() {
+        let dir = ScratchDir::new("placement-existing");
+        let source = dir.join("The.Show.S01E02.mkv");
+        fs::write(&source, [0; 10]).unwrap();
+        let roots = [dir.join("a"), dir.join("b")];
+        fs::create_dir_all(roots[1].join("media/tv/the.show")).unwrap();
+        fs::create_dir_all(&roots[0]).unwrap();
+        let config = config(json!({ "tvDir": "media/tv", "placement": "existingShow" }));
+        let watch = &config.watch_paths()[0];
+
+        let target = Path::new("media/tv/the.show/01/The.Show.S01E02.mkv");
+        assert_eq!(
+            choose_root(&source, target, watch, &roots, 0).unwrap(),
+            roots[1]
+        );
+        let other = Path::new("media/tv/other.show/01/Other.Show.S01E02.mkv");
+        assert!(roots.contains(&choose_root(&source, other, watch, &roots, 0).unwrap()));
+    }
+
+    #[test]
+    fn roots_without_room_for_the_margin_are_refused() {
+        let dir = ScratchDir::new("placement-full");
+        let source = dir.join("Some.Movie.2020.mkv");
+        fs::write(&source, [0; 10]).unwrap();
+        let config = config(json!({}));
+        let watch = &config.watch_paths()[0];
+        let target = Path::new("movie/Some.Movie.2020.mkv");
+
+        let roots = [dir.path().to_path_buf()];
+        assert!(choose_root(&source, target, watch, &roots, 0).is_ok());
+        let error = choose_root(&source, target, watch, &roots, u64::MAX).unwrap_err();
+        assert!(
+            error.to_string().starts_with("not enough free space"),
+            "{}",
+            error
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlinks_on_the_same_disk_need_no_space() {
+        let dir = ScratchDir::new("placement-hardlink");
+        let source = dir.join("Some.Movie.2020.mkv");
+        fs::write(&source, [0; 10]).unwrap();
+        let root = dir.join("library");
+        assert_eq!(needed_space(&source, &root, ImportMode::Hardlink, 10), 0);
+        assert_eq!(needed_space(&source, &root, ImportMode::Copy, 10), 10);
+    }
+}
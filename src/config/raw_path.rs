@@ -0,0 +1,84 @@
+//! Serde helpers that keep paths byte for byte. UTF-8 paths are written as
+//! plain strings, anything else as `{"bytes": [...]}`.
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawPath {
+    Utf8(String),
+    Bytes { bytes: Vec<u8> },
+}
+
+#[cfg(unix)]
+fn to_raw(path: &Path) -> RawPath {
+    use std::os::unix::ffi::OsStrExt;
+    match path.to_str() {
+        Some(utf8) => RawPath::Utf8(utf8.to_string()),
+        None => RawPath::Bytes {
+            bytes: path.as_os_str().as_bytes().to_vec(),
+        },
+    }
+}
+
+#[cfg(not(unix))]
+fn to_raw(path: &Path) -> RawPath {
+    RawPath::Utf8(path.to_string_lossy().to_string())
+}
+
+#[cfg(unix)]
+fn from_raw(raw: RawPath) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    match raw {
+        RawPath::Utf8(utf8) => PathBuf::from(utf8),
+        RawPath::Bytes { bytes } => PathBuf::from(OsStr::from_bytes(&bytes)),
+    }
+}
+
+#[cfg(not(unix))]
+fn from_raw(raw: RawPath) -> PathBuf {
+    match raw {
+        RawPath::Utf8(utf8) => PathBuf::from(utf8),
+        RawPath::Bytes { bytes } => PathBuf::from(String::from_utf8_lossy(&bytes).to_string()),
+    }
+}
+
+/// A path's bytes, for stores that keep them as a blob.
+#[cfg(unix)]
+pub fn to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+pub fn to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().as_bytes().to_vec()
+}
+
+pub fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+    from_raw(RawPath::Bytes { bytes })
+}
+
+pub fn serialize<S: Serializer>(path: &Path, s: S) -> Result<S::Ok, S::Error> {
+    to_raw(path).serialize(s)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<PathBuf, D::Error> {
+    Ok(from_raw(RawPath::deserialize(d)?))
+}
+
+/// The same, for optional paths.
+pub mod option {
+    use super::{from_raw, to_raw, RawPath};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::PathBuf;
+
+    pub fn serialize<S: Serializer>(path: &Option<PathBuf>, s: S) -> Result<S::Ok, S::Error> {
+        path.as_deref().map(to_raw).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<PathBuf>, D::Error> {
+        Ok(Option::<RawPath>::deserialize(d)?.map(from_raw))
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::{bail, Result as AnyResult};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest file name most filesystems, and Samba, will accept, in bytes.
+const MAX_COMPONENT_BYTES: usize = 255;
+
+/// Characters Windows clients cannot use in a name, on top of control characters.
+const ILLEGAL: [char; 9] = ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns a name taken from a download into a single safe path component:
+/// NFC normalised, no separators or control characters, not `.`/`..` or a
+/// reserved device name, no trailing dots or spaces and at most 255 bytes.
+pub fn sanitise_component(raw: &str) -> String {
+    let mut name: String = raw
+        .nfc()
+        .map(|c| {
+            if c.is_control() || ILLEGAL.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    name = name.trim().trim_end_matches(['.', ' ']).to_string();
+
+    let stem = name.split('.').next().unwrap_or("").to_uppercase();
+    if RESERVED.contains(&stem.as_str()) {
+        name.insert(0, '_');
+    }
+    if name.is_empty() {
+        name.push('_');
+    }
+    truncate_keeping_extension(name)
+}
+
+/// Same as `sanitise_component`, but a name that is not UTF-8 keeps its
+/// original bytes, with only the unsafe ones replaced.
+#[cfg(unix)]
+pub fn sanitise_os_component(raw: &OsStr) -> OsString {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    if let Some(utf8) = raw.to_str() {
+        return OsString::from(sanitise_component(utf8));
+    }
+    let mut bytes: Vec<u8> = raw
+        .as_bytes()
+        .iter()
+        .map(|&b| {
+            if b < 0x20 || b == 0x7f || ILLEGAL.contains(&(b as char)) {
+                b'_'
+            } else {
+                b
+            }
+        })
+        .collect();
+    while let Some(b'.' | b' ') = bytes.last() {
+        bytes.pop();
+    }
+    if bytes.len() > MAX_COMPONENT_BYTES {
+        let extension = match bytes.iter().rposition(|&b| b == b'.') {
+            Some(dot) if bytes.len() - dot <= 16 => bytes[dot..].to_vec(),
+            _ => vec![],
+        };
+        bytes.truncate(MAX_COMPONENT_BYTES - extension.len());
+        bytes.extend(extension);
+    }
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+pub fn sanitise_os_component(raw: &OsStr) -> OsString {
+    OsString::from(sanitise_component(&raw.to_string_lossy()))
+}
+
+fn truncate_keeping_extension(name: String) -> String {
+    if name.len() <= MAX_COMPONENT_BYTES {
+        return name;
+    }
+    let extension = match name.rfind('.') {
+        Some(dot) if name.len() - dot <= 16 => name[dot..].to_string(),
+        _ => String::new(),
+    };
+    let mut stem_end = MAX_COMPONENT_BYTES - extension.len();
+    while !name.is_char_boundary(stem_end) {
+        stem_end -= 1;
+    }
+    format!("{}{}", &name[..stem_end], extension)
+}
+
+/// Resolves `.` and `..` without touching the filesystem.
+fn lexical(path: &Path) -> PathBuf {
+    let mut clean = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::ParentDir => {
+                clean.pop();
+            }
+            Component::CurDir => (),
+            other => clean.push(other),
+        }
+    }
+    clean
+}
+
+/// Canonicalises the deepest part of `path` that exists, so a symlinked
+/// directory inside the library cannot point somewhere else.
+fn resolve_existing(path: &Path) -> PathBuf {
+    let mut existing = path.to_path_buf();
+    let mut rest = vec![];
+    while !existing.exists() {
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                rest.push(name.to_os_string());
+                existing = parent.to_path_buf();
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+    let mut resolved = fs::canonicalize(&existing).unwrap_or(existing);
+    rest.iter().rev().for_each(|name| resolved.push(name));
+    resolved
+}
+
+/// Refuses any target that would end up outside `root`.
+pub fn ensure_within(root: &Path, path: &Path) -> AnyResult<()> {
+    let root = resolve_existing(&lexical(root));
+    let target = resolve_existing(&lexical(path));
+    if target == root || !target.starts_with(&root) {
+        bail!("{:?} is outside of {:?}", path, root)
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    #[test]
+    fn components_lose_separators_and_control_characters() {
+        assert_eq!(sanitise_component("../../etc/passwd"), ".._.._etc_passwd");
+        assert_eq!(sanitise_component("a\u{0}b\nc"), "a_b_c");
+        assert_eq!(sanitise_component("What? Why: <Now>"), "What_ Why_ _Now_");
+        assert_eq!(sanitise_component(".."), "_");
+        assert_eq!(sanitise_component("."), "_");
+        assert_eq!(sanitise_component("  Show Name. . "), "Show Name");
+    }
+
+    #[test]
+    fn reserved_device_names_are_prefixed() {
+        assert_eq!(sanitise_component("CON"), "_CON");
+        assert_eq!(sanitise_component("lpt1.mkv"), "_lpt1.mkv");
+        assert_eq!(sanitise_component("CONTACT.mkv"), "CONTACT.mkv");
+    }
+
+    #[test]
+    fn names_are_normalised_to_nfc() {
+        assert_eq!(sanitise_component("Ame\u{301}lie"), "Am\u{e9}lie");
+    }
+
+    #[test]
+    fn long_names_keep_their_extension_and_char_boundaries() {
+        let long = format!("{}.mkv", "\u{e9}".repeat(200));
+        let name = sanitise_component(&long);
+        assert!(name.len() <= MAX_COMPONENT_BYTES);
+        assert!(name.ends_with(".mkv"));
+        assert!(name.trim_end_matches(".mkv").chars().all(|c| c == '\u{e9}'));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_keep_their_bytes() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let raw = OsStr::from_bytes(b"Caf\xe9/Show\x01.mkv.");
+        assert_eq!(
+            sanitise_os_component(raw),
+            OsString::from_vec(b"Caf\xe9_Show_.mkv".to_vec())
+        );
+        assert_eq!(sanitise_os_component(OsStr::new("a:b")), "a_b");
+    }
+
+    #[test]
+    fn targets_must_stay_inside_the_root() {
+        let dir = ScratchDir::new("sanitise");
+        let root = dir.join("library");
+        fs::create_dir_all(&root).unwrap();
+        assert!(ensure_within(&root, &root.join("tv/show/01")).is_ok());
+        assert!(ensure_within(&root, &root.join("tv/../movie")).is_ok());
+        assert!(ensure_within(&root, &root.join("../elsewhere")).is_err());
+        assert!(ensure_within(&root, &root).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_refused() {
+        let dir = ScratchDir::new("sanitise-symlink");
+        let root = dir.join("library");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), root.join("tv")).unwrap();
+        assert!(ensure_within(&root, &root.join("tv/show")).is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use serde_json::from_slice;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Deserialize, Default, Debug)]
 pub enum Auth {
@@ -18,10 +19,478 @@ struct RssConfig {
     auth: Auth,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct StabiliseConfig {
+    /// Seconds an entry must go without size or mtime changes before it is moved.
+    #[serde(alias = "quietSecs", default = "default_quiet_secs")]
+    pub quiet_secs: u64,
+    /// Suffixes of files that are still being written, e.g. `.part`.
+    #[serde(alias = "ignoreExtensions", default = "default_ignore_extensions")]
+    pub ignore_extensions: Vec<String>,
+}
+
+fn default_quiet_secs() -> u64 {
+    30
+}
+
+fn default_ignore_extensions() -> Vec<String> {
+    vec![".part".to_string(), ".!qB".to_string(), ".tmp".to_string()]
+}
+
+impl Default for StabiliseConfig {
+    fn default() -> Self {
+        Self {
+            quiet_secs: default_quiet_secs(),
+            ignore_extensions: default_ignore_extensions(),
+        }
+    }
+}
+
+impl StabiliseConfig {
+    pub fn quiet_period(&self) -> Duration {
+        Duration::from_secs(self.quiet_secs)
+    }
+    pub fn is_in_progress(&self, path: &Path) -> bool {
+        path.file_name()
+            .map(|name| name.to_string_lossy())
+            .map(|name| {
+                self.ignore_extensions
+                    .iter()
+                    .any(|ext| name.ends_with(ext.as_str()))
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// How changes in the watch path are detected.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// inotify and friends, via notify's `RecommendedWatcher`.
+    Native,
+    /// notify's `PollWatcher`, for NFS and SMB mounts where native events never fire.
+    Poll,
+    /// Poll on network filesystems, native everywhere else.
+    #[default]
+    Auto,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WatcherConfig {
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(alias = "pollSecs", default = "default_poll_secs")]
+    pub poll_secs: u64,
+}
+
+fn default_poll_secs() -> u64 {
+    10
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::default(),
+            poll_secs: default_poll_secs(),
+        }
+    }
+}
+
+impl WatcherConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_secs)
+    }
+}
+
+/// What happens to the source once it has been placed in the library.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Copy, then delete the source.
+    #[default]
+    Move,
+    /// Copy and leave the source, e.g. so a torrent keeps seeding.
+    Copy,
+    /// Hardlink each file, falling back to a copy across filesystems.
+    Hardlink,
+}
+
+/// Owner and mode for everything the mover creates. Unset fields are left
+/// as the daemon created them.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Permissions {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    #[serde(alias = "fileMode", deserialize_with = "octal", default)]
+    pub file_mode: Option<u32>,
+    #[serde(alias = "dirMode", deserialize_with = "octal", default)]
+    pub dir_mode: Option<u32>,
+    /// Set the setgid bit on created directories so new files inherit the group.
+    #[serde(default)]
+    pub setgid: bool,
+}
+
+impl Permissions {
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none()
+            && self.gid.is_none()
+            && self.file_mode.is_none()
+            && self.dir_mode.is_none()
+            && !self.setgid
+    }
+}
+
+/// Modes are written as octal strings, e.g. `"0640"`, or as plain numbers.
+fn octal<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Number(u32),
+        Text(String),
+    }
+    match Option::<Mode>::deserialize(d)? {
+        None => Ok(None),
+        Some(Mode::Number(mode)) => Ok(Some(mode)),
+        Some(Mode::Text(text)) => u32::from_str_radix(text.trim_start_matches("0o"), 8)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// An external command run when something happens, see `hooks::client`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Hook {
+    /// Program followed by its arguments.
+    pub command: Vec<String>,
+    #[serde(alias = "timeoutSecs", default = "default_hook_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Extra attempts after a non-zero exit or a timeout.
+    #[serde(default)]
+    pub retries: u32,
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    300
+}
+
+impl Hook {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HooksConfig {
+    #[serde(alias = "onImport", default)]
+    pub on_import: Vec<Hook>,
+    #[serde(alias = "onFailure", default)]
+    pub on_failure: Vec<Hook>,
+    #[serde(alias = "onGrab", default)]
+    pub on_grab: Vec<Hook>,
+}
+
+/// A media server to tell about new files so it does not wait for its next scan.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MediaServer {
+    Jellyfin {
+        url: String,
+        #[serde(alias = "apiKey")]
+        api_key: String,
+    },
+    Emby {
+        url: String,
+        #[serde(alias = "apiKey")]
+        api_key: String,
+    },
+    Plex {
+        url: String,
+        token: String,
+        #[serde(alias = "sectionId")]
+        section_id: u32,
+    },
+    Kodi {
+        url: String,
+        user: Option<String>,
+        password: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MediaServerConfig {
+    #[serde(flatten)]
+    pub server: MediaServer,
+    /// Rewrites the start of imported paths when the server mounts the
+    /// library somewhere else, e.g. `/mnt` here and `/media` there.
+    #[serde(alias = "pathFrom")]
+    pub path_from: Option<String>,
+    #[serde(alias = "pathTo")]
+    pub path_to: Option<String>,
+}
+
+/// Kodi style `.nfo` files written next to imported media.
+#[derive(Deserialize, Debug, Clone)]
+pub struct NfoConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Where the title, description and date of grabbed RSS items are kept
+    /// until their download is imported.
+    #[serde(alias = "metadataPath", default = "default_metadata_path")]
+    pub metadata_path: String,
+    /// Items older than this are dropped from the metadata file.
+    #[serde(alias = "metadataDays", default = "default_metadata_days")]
+    pub metadata_days: u64,
+}
+
+fn default_metadata_path() -> String {
+    "./metadata".to_string()
+}
+
+fn default_metadata_days() -> u64 {
+    30
+}
+
+impl Default for NfoConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            metadata_path: default_metadata_path(),
+            metadata_days: default_metadata_days(),
+        }
+    }
+}
+
+impl NfoConfig {
+    pub fn metadata_path(&self) -> &Path {
+        Path::new(self.metadata_path.as_str())
+    }
+}
+
+/// Where the history of grabbed RSS items is kept.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryBackend {
+    /// Nothing is written to disk, for tests and dry runs.
+    Memory,
+    /// The original pretty printed list of links.
+    Json,
+    #[default]
+    Sqlite,
+}
+
+/// What makes two RSS items the same download, across every feed.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupeKey {
+    /// The item's `<guid>`.
+    Guid,
+    /// The link with its query string removed.
+    #[default]
+    Link,
+    /// The torrent infohash, from torznab attributes or a magnet link.
+    Infohash,
+    /// Show, season and episode parsed from the title.
+    Episode,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+pub struct DedupeConfig {
+    #[serde(default)]
+    pub key: DedupeKey,
+    /// With the `episode` key, grab an episode again if a higher resolution release turns up.
+    #[serde(default)]
+    pub upgrades: bool,
+}
+
+/// How long history entries are kept. Items still in a feed are always kept.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RetentionConfig {
+    /// Forget items that have not been in a feed for this many days.
+    pub days: Option<u64>,
+    /// Keep only this many of the most recently seen items per feed.
+    #[serde(alias = "perFeed")]
+    pub per_feed: Option<usize>,
+    #[serde(alias = "pruneSecs", default = "default_prune_secs")]
+    pub prune_secs: u64,
+}
+
+fn default_prune_secs() -> u64 {
+    24 * 60 * 60
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            days: None,
+            per_feed: None,
+            prune_secs: default_prune_secs(),
+        }
+    }
+}
+
+impl RetentionConfig {
+    pub fn is_empty(&self) -> bool {
+        self.days.is_none() && self.per_feed.is_none()
+    }
+    pub fn prune_interval(&self) -> Duration {
+        Duration::from_secs(self.prune_secs.max(1))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HistoryConfig {
+    #[serde(default)]
+    pub backend: HistoryBackend,
+    /// Also read once by the SQLite backend to import links seen before it existed.
+    #[serde(alias = "jsonPath", default = "default_json_path")]
+    pub json_path: String,
+    #[serde(alias = "sqlitePath", default = "default_sqlite_path")]
+    pub sqlite_path: String,
+    #[serde(default)]
+    pub dedupe: DedupeConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+}
+
+fn default_json_path() -> String {
+    "./save".to_string()
+}
+
+fn default_sqlite_path() -> String {
+    "./history.db".to_string()
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            backend: HistoryBackend::default(),
+            json_path: default_json_path(),
+            sqlite_path: default_sqlite_path(),
+            dedupe: Default::default(),
+            retention: Default::default(),
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn json_path(&self) -> &Path {
+        Path::new(self.json_path.as_str())
+    }
+    pub fn sqlite_path(&self) -> &Path {
+        Path::new(self.sqlite_path.as_str())
+    }
+}
+
+/// How to pick a library root when several are configured.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Placement {
+    /// The root with the most free space.
+    #[default]
+    MostFree,
+    /// The root that already has the show's folder, else the one with most free space.
+    ExistingShow,
+}
+
+/// One watched directory as written in the config. Anything left out falls
+/// back to the top level setting of the same name.
+#[derive(Deserialize, Debug)]
+struct WatchPathSetting {
+    path: String,
+    #[serde(alias = "saveDir")]
+    save_dir: Option<String>,
+    #[serde(alias = "movieDir")]
+    movie_dir: Option<String>,
+    #[serde(alias = "tvDir")]
+    tv_dir: Option<String>,
+    #[serde(default)]
+    mode: ImportMode,
+    stabilise: Option<StabiliseConfig>,
+    watcher: Option<WatcherConfig>,
+    permissions: Option<Permissions>,
+    #[serde(alias = "libraryRoots")]
+    library_roots: Option<Vec<String>>,
+    placement: Option<Placement>,
+    /// How many levels below `path` a download lives, 1 being direct children.
+    #[serde(default = "default_depth")]
+    depth: usize,
+}
+
+fn default_depth() -> usize {
+    1
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WatchPathEntry {
+    Path(String),
+    Full(WatchPathSetting),
+}
+
+impl From<WatchPathEntry> for WatchPathSetting {
+    fn from(entry: WatchPathEntry) -> Self {
+        match entry {
+            WatchPathEntry::Full(setting) => setting,
+            WatchPathEntry::Path(path) => WatchPathSetting {
+                path,
+                save_dir: None,
+                movie_dir: None,
+                tv_dir: None,
+                mode: ImportMode::default(),
+                stabilise: None,
+                watcher: None,
+                permissions: None,
+                library_roots: None,
+                placement: None,
+                depth: default_depth(),
+            },
+        }
+    }
+}
+
+/// Accepts a single path, as older configs have, or a list of paths and settings.
+fn one_or_many<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<WatchPathSetting>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(WatchPathEntry),
+        Many(Vec<WatchPathEntry>),
+    }
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(entry) => vec![entry.into()],
+        OneOrMany::Many(entries) => entries.into_iter().map(Into::into).collect(),
+    })
+}
+
+/// A watched directory with every setting resolved against the top level config.
+#[derive(Debug, Clone)]
+pub struct WatchPath {
+    pub path: PathBuf,
+    pub save_dir: String,
+    pub movie_dir: Option<String>,
+    pub tv_dir: Option<String>,
+    pub mode: ImportMode,
+    pub stabilise: StabiliseConfig,
+    pub watcher: WatcherConfig,
+    pub permissions: Permissions,
+    /// Disks the library is spread over. Just `save_dir` unless configured.
+    pub library_roots: Vec<PathBuf>,
+    pub placement: Placement,
+    pub depth: usize,
+}
+
+impl WatchPath {
+    pub fn save_path(&self) -> &Path {
+        Path::new(self.save_dir.as_str())
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct MovieConfig {
-    #[serde(alias = "watch")]
-    pub watch_path: String,
+    #[serde(alias = "watch", deserialize_with = "one_or_many")]
+    watch_path: Vec<WatchPathSetting>,
+    #[serde(skip)]
+    watch_paths: Vec<WatchPath>,
     #[serde(alias = "saveDir")]
     pub save_dir: String,
     #[serde(alias = "movieDir")]
@@ -30,33 +499,153 @@ pub struct MovieConfig {
     pub tv_dir: Option<String>,
     #[serde(alias = "rss")]
     rss_config: RssConfig,
+    #[serde(alias = "journal", default = "default_journal_path")]
+    pub journal_path: String,
+    #[serde(default)]
+    pub stabilise: StabiliseConfig,
+    #[serde(alias = "units", default = "default_units_path")]
+    pub units_path: String,
+    /// Rescan the watch path every this many seconds, on top of the startup scan.
+    #[serde(alias = "rescanSecs", default)]
+    pub rescan_secs: Option<u64>,
+    #[serde(default)]
+    pub watcher: WatcherConfig,
+    /// How often Transmission is asked how grabbed torrents are getting on.
+    #[serde(alias = "torrentPollSecs", default = "default_torrent_poll_secs")]
+    pub torrent_poll_secs: u64,
+    /// How many imports may copy files at the same time.
+    #[serde(alias = "importWorkers", default = "default_import_workers")]
+    pub import_workers: usize,
+    /// Where downloads go when no library location can be worked out for them.
+    /// Defaults to `quarantine` under the watch path's save dir.
+    #[serde(alias = "quarantineDir")]
+    pub quarantine_dir: Option<String>,
+    #[serde(default)]
+    pub permissions: Permissions,
+    #[serde(alias = "libraryRoots", default)]
+    pub library_roots: Vec<String>,
+    #[serde(default)]
+    pub placement: Placement,
+    /// Space to leave free on a disk after an import, in MiB.
+    #[serde(alias = "freeSpaceMarginMb", default = "default_free_space_margin_mb")]
+    pub free_space_margin_mb: u64,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(alias = "mediaServers", default)]
+    pub media_servers: Vec<MediaServerConfig>,
+    #[serde(default)]
+    pub nfo: NfoConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// URLs that get a JSON POST for every notification.
+    #[serde(alias = "notify", default)]
+    pub notifications: Vec<String>,
 }
 
-impl MovieConfig {
-    pub fn save_path(&self) -> &Path {
-        Path::new(self.save_dir.as_str())
+fn default_journal_path() -> String {
+    "./journal".to_string()
+}
+
+fn default_free_space_margin_mb() -> u64 {
+    1024
+}
+
+fn default_torrent_poll_secs() -> u64 {
+    60
+}
+
+fn default_import_workers() -> usize {
+    2
+}
+
+fn default_units_path() -> String {
+    "./units".to_string()
+}
+
+impl Default for MovieConfig {
+    fn default() -> Self {
+        MovieConfig {
+            watch_path: vec![WatchPathEntry::Path("/srv/done".to_string()).into()],
+            watch_paths: vec![],
+            save_dir: "/mnt".to_string(),
+            movie_dir: Some("movie".to_string()),
+            tv_dir: Some("adult".to_string()),
+            rss_config: Default::default(),
+            journal_path: default_journal_path(),
+            stabilise: Default::default(),
+            units_path: default_units_path(),
+            rescan_secs: None,
+            watcher: Default::default(),
+            torrent_poll_secs: default_torrent_poll_secs(),
+            import_workers: default_import_workers(),
+            quarantine_dir: None,
+            permissions: Default::default(),
+            library_roots: vec![],
+            placement: Default::default(),
+            free_space_margin_mb: default_free_space_margin_mb(),
+            hooks: Default::default(),
+            media_servers: vec![],
+            nfo: Default::default(),
+            history: Default::default(),
+            notifications: vec![],
+        }
     }
+}
+
+impl MovieConfig {
     pub fn new(file: Option<Vec<u8>>) -> Self {
-        match file {
-            None => MovieConfig {
-                watch_path: "/srv/done".to_string(),
-                save_dir: "/mnt".to_string(),
-                movie_dir: Some("movie".to_string()),
-                tv_dir: Some("adult".to_string()),
-                rss_config: Default::default(),
-            },
+        let mut config = match file {
+            None => MovieConfig::default(),
             Some(file) => {
                 let test = from_slice::<MovieConfig>(&file);
                 println!("{:?}", test);
-                from_slice::<MovieConfig>(&file).unwrap_or(MovieConfig {
-                    watch_path: "/srv/done".to_string(),
-                    save_dir: "/mnt".to_string(),
-                    movie_dir: Some("movie".to_string()),
-                    tv_dir: Some("adult".to_string()),
-                    rss_config: Default::default(),
-                })
+                from_slice::<MovieConfig>(&file).unwrap_or_default()
             }
-        }
+        };
+        config.watch_paths = config.resolve_watch_paths();
+        config
+    }
+    fn resolve_watch_paths(&self) -> Vec<WatchPath> {
+        self.watch_path
+            .iter()
+            .map(|setting| {
+                let save_dir = setting
+                    .save_dir
+                    .clone()
+                    .unwrap_or_else(|| self.save_dir.clone());
+                let library_roots = match &setting.library_roots {
+                    Some(roots) => roots.clone(),
+                    None if !self.library_roots.is_empty() => self.library_roots.clone(),
+                    None => vec![save_dir.clone()],
+                };
+                (setting, save_dir, library_roots)
+            })
+            .map(|(setting, save_dir, library_roots)| WatchPath {
+                path: PathBuf::from(&setting.path),
+                save_dir,
+                movie_dir: setting.movie_dir.clone().or_else(|| self.movie_dir.clone()),
+                tv_dir: setting.tv_dir.clone().or_else(|| self.tv_dir.clone()),
+                mode: setting.mode,
+                stabilise: setting
+                    .stabilise
+                    .clone()
+                    .unwrap_or_else(|| self.stabilise.clone()),
+                watcher: setting
+                    .watcher
+                    .clone()
+                    .unwrap_or_else(|| self.watcher.clone()),
+                permissions: setting
+                    .permissions
+                    .clone()
+                    .unwrap_or_else(|| self.permissions.clone()),
+                library_roots: library_roots.iter().map(PathBuf::from).collect(),
+                placement: setting.placement.unwrap_or(self.placement),
+                depth: setting.depth.max(1),
+            })
+            .collect()
+    }
+    pub fn watch_paths(&self) -> &[WatchPath] {
+        &self.watch_paths
     }
     pub fn rss_feed(&self) -> &str {
         self.rss_config.feed.as_str()
@@ -67,4 +656,105 @@ impl MovieConfig {
     pub fn get_rpc_dest(&self) -> String {
         self.rss_config.dest.to_string()
     }
+    pub fn journal_path(&self) -> &Path {
+        Path::new(self.journal_path.as_str())
+    }
+    pub fn quarantine_path(&self, watch: &WatchPath) -> PathBuf {
+        match &self.quarantine_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => watch.save_path().join("quarantine"),
+        }
+    }
+    pub fn free_space_margin(&self) -> u64 {
+        self.free_space_margin_mb * 1024 * 1024
+    }
+    pub fn units_path(&self) -> &Path {
+        Path::new(self.units_path.as_str())
+    }
+    pub fn rescan_interval(&self) -> Option<Duration> {
+        self.rescan_secs
+            .map(|secs| Duration::from_secs(secs.max(1)))
+    }
+    pub fn torrent_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.torrent_poll_secs.max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::config;
+    use serde_json::json;
+
+    #[test]
+    fn a_single_watch_path_is_still_accepted() {
+        let config = config(json!({ "watch": "/srv/done" }));
+        let [watch] = config.watch_paths() else {
+            panic!("expected one watch path")
+        };
+        assert_eq!(watch.path, PathBuf::from("/srv/done"));
+        assert_eq!(watch.save_dir, "/srv/library");
+        assert_eq!(watch.mode, ImportMode::Move);
+        assert_eq!(watch.depth, 1);
+        assert_eq!(watch.library_roots, vec![PathBuf::from("/srv/library")]);
+    }
+
+    #[test]
+    fn watch_paths_fall_back_to_the_top_level_settings() {
+        let config = config(json!({
+            "stabilise": { "quietSecs": 5 },
+            "watch": [
+                "/srv/done",
+                {
+                    "path": "/srv/usenet",
+                    "saveDir": "/srv/other",
+                    "tvDir": "series",
+                    "mode": "hardlink",
+                    "stabilise": { "quietSecs": 90 },
+                    "depth": 0,
+                },
+            ],
+        }));
+        let [done, usenet] = config.watch_paths() else {
+            panic!("expected two watch paths")
+        };
+        assert_eq!(done.stabilise.quiet_secs, 5);
+        assert_eq!(done.tv_dir.as_deref(), Some("tv"));
+
+        assert_eq!(usenet.save_dir, "/srv/other");
+        assert_eq!(usenet.movie_dir.as_deref(), Some("movie"));
+        assert_eq!(usenet.tv_dir.as_deref(), Some("series"));
+        assert_eq!(usenet.mode, ImportMode::Hardlink);
+        assert_eq!(usenet.stabilise.quiet_secs, 90);
+        assert_eq!(usenet.depth, 1);
+        assert_eq!(usenet.library_roots, vec![PathBuf::from("/srv/other")]);
+    }
+
+    #[test]
+    fn library_roots_come_from_the_closest_setting() {
+        let config = config(json!({
+            "libraryRoots": ["/mnt/a", "/mnt/b"],
+            "watch": ["/srv/done", { "path": "/srv/drop", "libraryRoots": ["/mnt/c"] }],
+        }));
+        let roots: Vec<&[PathBuf]> = config
+            .watch_paths()
+            .iter()
+            .map(|watch| watch.library_roots.as_slice())
+            .collect();
+        assert_eq!(
+            roots,
+            [
+                &[PathBuf::from("/mnt/a"), PathBuf::from("/mnt/b")][..],
+                &[PathBuf::from("/mnt/c")][..],
+            ]
+        );
+    }
+
+    #[test]
+    fn poll_intervals_are_never_zero() {
+        let zero = config(json!({ "rescanSecs": 0, "torrentPollSecs": 0 }));
+        assert_eq!(zero.rescan_interval(), Some(Duration::from_secs(1)));
+        assert_eq!(zero.torrent_poll_interval(), Duration::from_secs(1));
+        assert_eq!(config(json!({})).rescan_interval(), None);
+    }
 }
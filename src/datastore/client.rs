@@ -1,14 +1,59 @@
-use serde_json::to_vec_pretty;
+use super::dedupe::normalise_show;
+use crate::config::atomic::write_atomic;
+use anyhow::{bail, Context};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, to_vec_pretty, Value};
 use std::cell::RefCell;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How many previous versions of the save file are kept as `<file>.1`, `<file>.2`, ...
+const BACKUPS: usize = 3;
+
+/// Schema version this build writes. Bump it together with a new step in `MIGRATIONS`.
+const VERSION: u64 = 4;
+
+/// `MIGRATIONS[n]` turns a version `n + 1` document into version `n + 2`.
+const MIGRATIONS: [fn(Value) -> anyhow::Result<Value>; 3] = [from_v1, from_v2, from_v3];
+
+/// When a key's item was last in a feed, and which one, for retention.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Seen {
+    pub feed: String,
+    pub last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SaveFile {
+    version: u64,
+    keys: HashSet<String>,
+    seen: HashMap<String, Seen>,
+    /// When each feed's latest poll started.
+    polls: HashMap<String, u64>,
+}
+
+/// The save file was written by a newer build, so nothing here can read it.
+#[derive(Debug)]
+pub struct UnknownVersion(u64);
+
+impl std::fmt::Display for UnknownVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "schema version {} is newer than the supported {}",
+            self.0, VERSION
+        )
+    }
+}
+
+impl std::error::Error for UnknownVersion {}
 
 pub trait Savable {
     type A;
 
-    fn add(client: &RefCell<Client>, item: &Self::A);
+    fn add(client: &RefCell<Client>, item: &Self::A) -> anyhow::Result<()>;
     fn check(client: &RefCell<Client>, item: &Self::A) -> bool;
     fn save(client: &RefCell<Client>) -> anyhow::Result<()>;
 }
@@ -21,6 +66,11 @@ pub trait Restorable {
 pub struct Client {
     file: PathBuf,
     set: HashSet<String>,
+    seen: HashMap<String, Seen>,
+    polls: HashMap<String, u64>,
+    /// Backups are rotated on the first save only, so they hold what the
+    /// file looked like at the last few starts rather than the last few grabs.
+    rotated: bool,
 }
 
 impl Client {
@@ -28,34 +78,177 @@ impl Client {
         Self {
             file,
             set: HashSet::new(),
+            seen: HashMap::new(),
+            polls: HashMap::new(),
+            rotated: false,
         }
     }
+    /// A client for `MemorySave`, whose file is never touched.
+    pub fn in_memory() -> Client {
+        Self::new(PathBuf::new())
+    }
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.set.iter()
+    }
+    /// Keys with when they were last in a feed. Keys confirmed before this
+    /// was kept have none.
+    pub fn seen(&self) -> &HashMap<String, Seen> {
+        &self.seen
+    }
+    pub fn polls(&self) -> &HashMap<String, u64> {
+        &self.polls
+    }
+    /// Notes that `key` was in `feed` at `at`. Saved with the next change.
+    pub fn touch(&mut self, key: &str, feed: &str, at: u64) {
+        let seen = Seen {
+            feed: feed.to_string(),
+            last_seen: at,
+        };
+        self.seen.insert(key.to_string(), seen);
+    }
+    /// Notes that a poll of `feed` started at `started`. Saved with the next change.
+    pub fn polled(&mut self, feed: &str, started: u64) {
+        self.polls.insert(feed.to_string(), started);
+    }
+    fn backup(&self, n: usize) -> PathBuf {
+        let mut name = self.file.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
 }
 pub struct RssSave {}
+/// Keeps the set in memory only.
+pub struct MemorySave {}
+
+/// Version 1 was a bare list of keys, with a lone `""` in files that started empty.
+fn from_v1(value: Value) -> anyhow::Result<Value> {
+    let keys: Vec<String> = serde_json::from_value(value)?;
+    let keys: Vec<String> = keys.into_iter().filter(|key| !key.is_empty()).collect();
+    Ok(json!({ "version": 2, "keys": keys }))
+}
+
+/// Version 2 episode keys kept the show as parsed, e.g. `the.show s01e02 q3`.
+fn from_v2(value: Value) -> anyhow::Result<Value> {
+    let episode = Regex::new(r"^(.+) (s\d{2}e\d{2,3}(?: q\d+)?)$").unwrap();
+    let keys: Vec<String> = serde_json::from_value(value["keys"].clone())?;
+    let keys: Vec<String> = keys
+        .into_iter()
+        .map(|key| match episode.captures(&key) {
+            Some(parts) if !key.contains("://") && !key.starts_with("magnet:") => {
+                format!("{} {}", normalise_show(&parts[1]), &parts[2])
+            }
+            _ => key,
+        })
+        .collect();
+    Ok(json!({ "version": 3, "keys": keys }))
+}
+
+/// Version 3 kept no times, so every key counts as seen at the upgrade,
+/// in no feed that is polled.
+fn from_v3(value: Value) -> anyhow::Result<Value> {
+    let keys: Vec<String> = serde_json::from_value(value["keys"].clone())?;
+    let now = crate::journal::client::now();
+    let seen: HashMap<&String, Seen> = keys
+        .iter()
+        .map(|key| {
+            let seen = Seen {
+                feed: String::new(),
+                last_seen: now,
+            };
+            (key, seen)
+        })
+        .collect();
+    Ok(json!({ "version": 4, "keys": keys, "seen": seen, "polls": {} }))
+}
+
+fn version_of(value: &Value) -> anyhow::Result<u64> {
+    match value {
+        Value::Array(_) => Ok(1),
+        _ => match value.get("version").and_then(Value::as_u64) {
+            Some(version) if version >= 1 => Ok(version),
+            _ => bail!("no schema version"),
+        },
+    }
+}
+
+/// Reads a save file of any known version, migrating it up to `VERSION`.
+fn read_file(file: &Path) -> anyhow::Result<SaveFile> {
+    let mut value: Value = serde_json::from_slice(&fs::read(file)?)?;
+    let version = version_of(&value)?;
+    if version > VERSION {
+        return Err(UnknownVersion(version).into());
+    }
+    for step in &MIGRATIONS[(version - 1) as usize..] {
+        value = step(value)?;
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// The keys of a save file of any known version.
+pub fn read_set(file: &Path) -> anyhow::Result<HashSet<String>> {
+    Ok(read_file(file)?.keys)
+}
+
+fn to_document(client: &Client) -> anyhow::Result<Vec<u8>> {
+    Ok(to_vec_pretty(&json!({
+        "version": VERSION,
+        "keys": client.set,
+        "seen": client.seen,
+        "polls": client.polls,
+    }))?)
+}
+
+impl Client {
+    fn load(&mut self, save: SaveFile) {
+        self.set = save.keys;
+        self.seen = save.seen;
+        self.polls = save.polls;
+    }
+}
 
 impl Restorable for Client {
+    /// Falls back to the newest backup that parses if the save file is
+    /// missing or corrupt, and writes the recovered set back. A file from a
+    /// newer build is refused rather than replaced by an older backup.
     fn restore(&mut self) -> anyhow::Result<()> {
-        if self.file.is_file() {
-            let mut file = File::open(self.file.as_path())?;
-            let mut buffer = String::new();
-            file.read_to_string(&mut buffer)?;
-
-            let set: HashSet<String> = serde_json::from_str(&buffer)?;
-            self.set = set;
-            anyhow::Ok(())
-        } else {
-            let mut f = File::create_new(&self.file)?;
-            f.write_all(&to_vec_pretty(&vec![""])?)?;
-            anyhow::Ok(())
+        let backups: Vec<PathBuf> = (1..=BACKUPS)
+            .map(|n| self.backup(n))
+            .filter(|f| f.is_file())
+            .collect();
+        if !self.file.is_file() && backups.is_empty() {
+            return Ok(write_atomic(&self.file, &to_document(self)?)?);
+        }
+        let error = match read_file(&self.file) {
+            Ok(save) => {
+                self.load(save);
+                return anyhow::Ok(());
+            }
+            Err(e) => e,
+        };
+        if error.is::<UnknownVersion>() {
+            return Err(error.context(format!("cannot load {:?}", self.file)));
+        }
+        println!("could not read {:?}: {:#}", self.file, error);
+        for backup in backups {
+            match read_file(&backup) {
+                Ok(save) => {
+                    println!("recovered {} links from {:?}", save.keys.len(), backup);
+                    self.load(save);
+                    write_atomic(&self.file, &to_document(self)?)?;
+                    return anyhow::Ok(());
+                }
+                Err(e) => println!("could not read {:?}: {:#}", backup, e),
+            }
         }
+        bail!("{:?} is unreadable and no backup could be used", self.file)
     }
 }
 impl Savable for RssSave {
     type A = String;
 
-    fn add(client: &RefCell<Client>, item: &Self::A) {
+    fn add(client: &RefCell<Client>, item: &Self::A) -> anyhow::Result<()> {
         client.borrow_mut().set.insert(item.to_owned());
-        save::<RssSave>(client).expect("TODO: panic message");
+        save::<RssSave>(client)
     }
 
     fn check(client: &RefCell<Client>, item: &Self::A) -> bool {
@@ -63,23 +256,171 @@ impl Savable for RssSave {
     }
 
     fn save(client: &RefCell<Client>) -> anyhow::Result<()> {
-        let mut file = File::create(client.borrow().file.as_path())?;
-        serde_json::to_string(&client.borrow().set.iter().collect::<Vec<_>>())?;
-        file.write_all(&to_vec_pretty(
-            &client.borrow().set.iter().collect::<Vec<_>>(),
-        )?)?;
-        anyhow::Ok(())
+        let mut client = client.borrow_mut();
+        if !client.rotated && client.file.is_file() {
+            for n in (1..BACKUPS).rev() {
+                if client.backup(n).is_file() {
+                    fs::rename(client.backup(n), client.backup(n + 1))?;
+                }
+            }
+            fs::copy(&client.file, client.backup(1))
+                .with_context(|| format!("could not back up {:?}", client.file))?;
+        }
+        client.rotated = true;
+        Ok(write_atomic(&client.file, &to_document(&client)?)?)
     }
 }
 
-fn save<Op: Savable>(client: &RefCell<Client>) -> anyhow::Result<()> {
+impl Savable for MemorySave {
+    type A = String;
+
+    fn add(client: &RefCell<Client>, item: &Self::A) -> anyhow::Result<()> {
+        client.borrow_mut().set.insert(item.to_owned());
+        Ok(())
+    }
+
+    fn check(client: &RefCell<Client>, item: &Self::A) -> bool {
+        client.borrow().set.contains(item)
+    }
+
+    fn save(_client: &RefCell<Client>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes out whatever `touch` and `polled` noted since the last change.
+pub fn save<Op: Savable>(client: &RefCell<Client>) -> anyhow::Result<()> {
     Op::save(client)
 }
+/// Only links Transmission has answered for are kept, so anything that is
+/// still pending or failed is sent again on the next poll or after a restart.
 pub fn check<Op: Savable>(item: &Op::A, client: &RefCell<Client>) -> bool {
-    if Op::check(client, item) {
-        true
-    } else {
-        Op::add(client, item);
-        false
+    Op::check(client, item)
+}
+pub fn confirm<Op: Savable>(item: &Op::A, client: &RefCell<Client>) -> anyhow::Result<()> {
+    Op::add(client, item)
+}
+/// Drops every entry `keep` says no to, returning how many went.
+pub fn retain<Op: Savable>(
+    client: &RefCell<Client>,
+    keep: impl Fn(&str) -> bool,
+) -> anyhow::Result<usize> {
+    let removed = {
+        let mut client = client.borrow_mut();
+        let before = client.set.len();
+        client.set.retain(|item| keep(item));
+        let Client { set, seen, .. } = &mut *client;
+        seen.retain(|key, _| set.contains(key));
+        before - set.len()
+    };
+    if removed > 0 {
+        Op::save(client)?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    fn open(file: &Path) -> RefCell<Client> {
+        let mut client = Client::new(file.to_path_buf());
+        client.restore().unwrap();
+        RefCell::new(client)
+    }
+
+    #[test]
+    fn backups_rotate_once_per_start() {
+        let dir = ScratchDir::new("save-rotate");
+        let file = dir.join("save.json");
+        let sizes = |client: &RefCell<Client>| -> Vec<usize> {
+            (1..=BACKUPS)
+                .map(|n| client.borrow().backup(n))
+                .filter(|backup| backup.exists())
+                .map(|backup| read_set(&backup).unwrap().len())
+                .collect()
+        };
+        let client = open(&file);
+        confirm::<RssSave>(&"a".to_string(), &client).unwrap();
+        assert_eq!(sizes(&client), [0]);
+
+        let client = open(&file);
+        confirm::<RssSave>(&"b".to_string(), &client).unwrap();
+        confirm::<RssSave>(&"c".to_string(), &client).unwrap();
+        assert_eq!(sizes(&client), [1, 0]);
+
+        let client = open(&file);
+        confirm::<RssSave>(&"d".to_string(), &client).unwrap();
+        confirm::<RssSave>(&"e".to_string(), &client).unwrap();
+        assert_eq!(sizes(&client), [3, 1, 0]);
+        assert_eq!(read_set(&file).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn a_corrupt_file_is_recovered_from_the_newest_backup() {
+        let dir = ScratchDir::new("save-recover");
+        let file = dir.join("save.json");
+        let client = open(&file);
+        confirm::<RssSave>(&"a".to_string(), &client).unwrap();
+        let client = open(&file);
+        confirm::<RssSave>(&"b".to_string(), &client).unwrap();
+        fs::write(&file, "{ truncated").unwrap();
+
+        let client = open(&file);
+        assert!(check::<RssSave>(&"a".to_string(), &client));
+        assert!(!check::<RssSave>(&"b".to_string(), &client));
+        assert_eq!(read_set(&file).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn version_2_episode_keys_get_normalised_shows() {
+        let dir = ScratchDir::new("save-v2");
+        let file = dir.join("save.json");
+        let keys = [
+            "the.show s01e02 q3",
+            "The_Show s01e03",
+            "http://a/1",
+            "magnet:?xt=urn:btih:ab&dn=x s01e02",
+        ];
+        fs::write(&file, json!({ "version": 2, "keys": keys }).to_string()).unwrap();
+        let mut read: Vec<String> = read_set(&file).unwrap().into_iter().collect();
+        read.sort();
+        assert_eq!(
+            read,
+            [
+                "http://a/1",
+                "magnet:?xt=urn:btih:ab&dn=x s01e02",
+                "the show s01e02 q3",
+                "the show s01e03",
+            ]
+        );
+    }
+
+    #[test]
+    fn version_3_keys_count_as_seen_at_the_upgrade() {
+        let dir = ScratchDir::new("save-v3");
+        let file = dir.join("save.json");
+        fs::write(
+            &file,
+            json!({ "version": 3, "keys": ["http://a/1"] }).to_string(),
+        )
+        .unwrap();
+        let before = crate::journal::client::now();
+        let client = open(&file);
+        let client = client.borrow();
+        let seen = &client.seen()["http://a/1"];
+        assert_eq!(seen.feed, "");
+        assert!(seen.last_seen >= before);
+        assert!(client.polls().is_empty());
+    }
+
+    #[test]
+    fn memory_saves_never_touch_the_disk() {
+        let client = RefCell::new(Client::in_memory());
+        confirm::<MemorySave>(&"a".to_string(), &client).unwrap();
+        assert!(check::<MemorySave>(&"a".to_string(), &client));
+        assert_eq!(retain::<MemorySave>(&client, |key| key != "a").unwrap(), 1);
+        assert!(!check::<MemorySave>(&"a".to_string(), &client));
     }
 }
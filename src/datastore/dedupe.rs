@@ -0,0 +1,175 @@
+use super::record::ItemRecord;
+use crate::config::types::{DedupeConfig, DedupeKey};
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// Resolution patterns and their rank, best first.
+static RANKS: LazyLock<[(Regex, u8); 4]> = LazyLock::new(|| {
+    [
+        (Regex::new(r"2160p|\b4k\b|\buhd\b").unwrap(), 4),
+        (Regex::new(r"1080[pi]").unwrap(), 3),
+        (Regex::new(r"720p").unwrap(), 2),
+        (Regex::new(r"480p|576p|\bsd(tv)?\b").unwrap(), 1),
+    ]
+});
+
+/// The link without its query string, so rotating download tokens do not
+/// make an item look new. Magnet links are all query, so they are kept whole.
+pub fn strip_query(link: &str) -> &str {
+    if link.starts_with("magnet:") {
+        return link;
+    }
+    link.split(['?', '#']).next().unwrap_or(link)
+}
+
+/// `The.Show_Name -` and `the show name` are the same show: lower case, with
+/// every run of punctuation and whitespace turned into one space.
+pub fn normalise_show(show: &str) -> String {
+    show.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `show s01e02`, if the title could be parsed that far.
+pub fn episode_key(record: &ItemRecord) -> Option<String> {
+    Some(format!(
+        "{} s{:02}e{:02}",
+        normalise_show(record.show.as_ref()?),
+        record.season?,
+        record.episode?
+    ))
+}
+
+/// Rough rank of a release by resolution, higher is better, 0 if unknown.
+pub fn quality(title: &str) -> u8 {
+    let title = title.to_lowercase();
+    RANKS
+        .iter()
+        .find(|(pattern, _)| pattern.is_match(&title))
+        .map(|(_, rank)| *rank)
+        .unwrap_or(0)
+}
+
+/// The string two items share if they are the same download. Items missing
+/// the configured field fall back to their stripped link.
+pub fn identity(config: &DedupeConfig, record: &ItemRecord) -> String {
+    let link = || strip_query(&record.link).to_string();
+    match config.key {
+        DedupeKey::Link => link(),
+        DedupeKey::Guid => record.guid.clone().unwrap_or_else(link),
+        DedupeKey::Infohash => record.infohash.clone().unwrap_or_else(link),
+        DedupeKey::Episode => episode_key(record).unwrap_or_else(link),
+    }
+}
+
+/// `identity`, plus the quality when upgrades are on, as kept in the JSON store.
+pub fn key(config: &DedupeConfig, record: &ItemRecord) -> String {
+    match episode_key(record) {
+        Some(episode) if config.key == DedupeKey::Episode && config.upgrades => format!(
+            "{} q{}",
+            episode,
+            quality(record.title.as_deref().unwrap_or_default())
+        ),
+        _ => identity(config, record),
+    }
+}
+
+/// Whether `record` should be grabbed even though a release of the same
+/// episode at `best` quality already was.
+pub fn is_upgrade(config: &DedupeConfig, record: &ItemRecord, best: u8) -> bool {
+    config.key == DedupeKey::Episode
+        && config.upgrades
+        && episode_key(record).is_some()
+        && quality(record.title.as_deref().unwrap_or_default()) > best
+}
+
+/// For keys from `key` with upgrades on: the quality a seen `show s01e02 q3` was grabbed at.
+pub fn seen_quality(stored: &str, episode: &str) -> Option<u8> {
+    stored
+        .strip_prefix(episode)?
+        .strip_prefix(" q")?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn episode(show: &str, title: &str) -> ItemRecord {
+        ItemRecord {
+            link: "http://a/1?token=x".to_string(),
+            guid: Some("guid-1".to_string()),
+            title: Some(title.to_string()),
+            show: Some(show.to_string()),
+            season: Some(1),
+            episode: Some(2),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn shows_are_compared_without_punctuation_or_case() {
+        assert_eq!(normalise_show("the.show"), "the show");
+        assert_eq!(normalise_show("The_Show  -"), "the show");
+        assert_eq!(
+            normalise_show("Marvel's Agents.of.S.H.I.E.L.D"),
+            "marvel s agents of s h i e l d"
+        );
+        assert_eq!(
+            episode_key(&episode("The.Show", "")),
+            episode_key(&episode("the show -", ""))
+        );
+    }
+
+    #[test]
+    fn only_http_links_lose_their_query() {
+        assert_eq!(strip_query("http://a/1?token=x#frag"), "http://a/1");
+        assert_eq!(strip_query("http://a/1#frag"), "http://a/1");
+        assert_eq!(
+            strip_query("magnet:?xt=urn:btih:ab"),
+            "magnet:?xt=urn:btih:ab"
+        );
+    }
+
+    #[test]
+    fn quality_goes_by_resolution() {
+        assert_eq!(quality("Show.S01E02.2160p.WEB"), 4);
+        assert_eq!(quality("Show S01E02 4K"), 4);
+        assert_eq!(quality("Show.S01E02.1080i"), 3);
+        assert_eq!(quality("Show.S01E02.720p"), 2);
+        assert_eq!(quality("Show.S01E02.SDTV"), 1);
+        assert_eq!(quality("Show.S01E02.4kids"), 0);
+    }
+
+    #[test]
+    fn keys_follow_the_configured_field() {
+        let record = episode("the.show", "The.Show.S01E02.1080p");
+        let config = |key, upgrades| DedupeConfig { key, upgrades };
+        assert_eq!(
+            identity(&config(DedupeKey::Link, false), &record),
+            "http://a/1"
+        );
+        assert_eq!(identity(&config(DedupeKey::Guid, false), &record), "guid-1");
+        assert_eq!(
+            identity(&config(DedupeKey::Infohash, false), &record),
+            "http://a/1"
+        );
+        assert_eq!(
+            identity(&config(DedupeKey::Episode, false), &record),
+            "the show s01e02"
+        );
+        let upgrades = config(DedupeKey::Episode, true);
+        assert_eq!(key(&upgrades, &record), "the show s01e02 q3");
+        assert_eq!(
+            seen_quality("the show s01e02 q3", "the show s01e02"),
+            Some(3)
+        );
+        assert_eq!(seen_quality("the show s01e03 q3", "the show s01e02"), None);
+        assert!(is_upgrade(&upgrades, &record, 2));
+        assert!(!is_upgrade(&upgrades, &record, 3));
+        assert!(!is_upgrade(&config(DedupeKey::Episode, false), &record, 2));
+    }
+}
@@ -0,0 +1,261 @@
+use super::record::{ImportRecord, ItemRecord, LifecycleEvent, Status, TorrentRecord};
+use super::store::Store;
+use crate::config::types::RetentionConfig;
+use anyhow::{anyhow, Ok as AnyOk, Result as AnyResult};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::oneshot::{channel as one_shot, Sender as OneSender};
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+
+type Reply<T> = OneSender<AnyResult<T>>;
+
+/// Everything the store can be asked, each with a channel for the answer.
+enum StoreRequest {
+    Check(Vec<ItemRecord>, Reply<Vec<bool>>),
+    Confirm(Vec<(ItemRecord, Status)>, Reply<()>),
+    Polled(String, u64, Reply<()>),
+    Prune(RetentionConfig, Reply<usize>),
+    AddTorrent(TorrentRecord, Reply<()>),
+    FindTorrent(String, Reply<Option<TorrentRecord>>),
+    AddImport(ImportRecord, Reply<()>),
+    AddEvent(LifecycleEvent, Reply<()>),
+    Tracked(Reply<Vec<LifecycleEvent>>),
+}
+
+/// A handle on the history store. Clones share one store, which lives on a
+/// thread of its own for the whole run, so disk and SQLite work never holds
+/// up the async tasks or takes a thread from the blocking pool.
+/// Requests are answered one at a time, in the order they arrive.
+#[derive(Clone)]
+pub struct Datastore {
+    tx: Sender<StoreRequest>,
+}
+
+impl Datastore {
+    /// The returned task ends once every handle has been dropped.
+    pub fn spawn(store: Box<dyn Store + Send>) -> (Self, JoinHandle<AnyResult<()>>) {
+        let (tx, mut rx) = channel(32);
+        let (done, finished) = one_shot::<()>();
+        std::thread::Builder::new()
+            .name("datastore".to_string())
+            .spawn(move || {
+                while let Some(request) = rx.blocking_recv() {
+                    serve(store.as_ref(), request);
+                }
+                let _ = done.send(());
+            })
+            .expect("could not start the datastore thread");
+        let task = tokio::spawn(async move {
+            finished
+                .await
+                .map_err(|_| anyhow!("the datastore thread panicked"))
+        });
+        (Self { tx }, task)
+    }
+
+    async fn request<T>(&self, request: impl FnOnce(Reply<T>) -> StoreRequest) -> AnyResult<T> {
+        let (reply, answer) = one_shot();
+        self.tx
+            .send(request(reply))
+            .await
+            .map_err(|_| anyhow!("the datastore has stopped"))?;
+        answer.await?
+    }
+
+    /// For each item, whether it was seen before.
+    pub async fn check(&self, records: Vec<ItemRecord>) -> AnyResult<Vec<bool>> {
+        self.request(|reply| StoreRequest::Check(records, reply))
+            .await
+    }
+
+    pub async fn confirm(&self, record: ItemRecord, status: Status) -> AnyResult<()> {
+        self.confirm_all(vec![(record, status)]).await
+    }
+
+    pub async fn confirm_all(&self, confirmed: Vec<(ItemRecord, Status)>) -> AnyResult<()> {
+        self.request(|reply| StoreRequest::Confirm(confirmed, reply))
+            .await
+    }
+
+    pub async fn polled(&self, feed: &str, started: u64) -> AnyResult<()> {
+        let feed = feed.to_string();
+        self.request(|reply| StoreRequest::Polled(feed, started, reply))
+            .await
+    }
+
+    pub async fn add_torrent(&self, torrent: TorrentRecord) -> AnyResult<()> {
+        self.request(|reply| StoreRequest::AddTorrent(torrent, reply))
+            .await
+    }
+
+    pub async fn find_torrent(&self, name: &str) -> AnyResult<Option<TorrentRecord>> {
+        let name = name.to_string();
+        self.request(|reply| StoreRequest::FindTorrent(name, reply))
+            .await
+    }
+
+    pub async fn add_import(&self, import: ImportRecord) -> AnyResult<()> {
+        self.request(|reply| StoreRequest::AddImport(import, reply))
+            .await
+    }
+
+    pub async fn add_event(&self, event: LifecycleEvent) -> AnyResult<()> {
+        self.request(|reply| StoreRequest::AddEvent(event, reply))
+            .await
+    }
+
+    /// The latest event of every item Transmission should still have.
+    pub async fn tracked(&self) -> AnyResult<Vec<LifecycleEvent>> {
+        self.request(StoreRequest::Tracked).await
+    }
+
+    /// Applies the retention policy now and then every `prune_secs`.
+    pub fn prune_every(&self, retention: RetentionConfig) -> JoinHandle<AnyResult<()>> {
+        let datastore = self.clone();
+        tokio::spawn(async move {
+            if retention.is_empty() {
+                return AnyOk(());
+            }
+            let mut ticks = interval(retention.prune_interval());
+            loop {
+                ticks.tick().await;
+                let retention = retention.clone();
+                match datastore
+                    .request(|reply| StoreRequest::Prune(retention, reply))
+                    .await
+                {
+                    Ok(0) => (),
+                    Ok(removed) => println!("pruned {} history entries", removed),
+                    Err(e) => println!("could not prune history: {:?}", e),
+                }
+            }
+        })
+    }
+}
+
+/// Nobody waiting for an answer is fine, so failed sends are ignored.
+fn serve(store: &dyn Store, request: StoreRequest) {
+    match request {
+        StoreRequest::Check(records, reply) => {
+            let _ = reply.send(store.check_all(&records));
+        }
+        StoreRequest::Confirm(confirmed, reply) => {
+            let _ = reply.send(store.confirm_all(&confirmed));
+        }
+        StoreRequest::Polled(feed, started, reply) => {
+            let _ = reply.send(store.polled(&feed, started));
+        }
+        StoreRequest::Prune(retention, reply) => {
+            let _ = reply.send(store.prune(&retention));
+        }
+        StoreRequest::AddTorrent(torrent, reply) => {
+            let _ = reply.send(store.add_torrent(&torrent));
+        }
+        StoreRequest::FindTorrent(name, reply) => {
+            let _ = reply.send(store.find_torrent(&name));
+        }
+        StoreRequest::AddImport(import, reply) => {
+            let _ = reply.send(store.add_import(&import));
+        }
+        StoreRequest::AddEvent(event, reply) => {
+            let _ = reply.send(store.add_event(&event));
+        }
+        StoreRequest::Tracked(reply) => {
+            let _ = reply.send(store.tracked());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::{DedupeConfig, DedupeKey};
+    use crate::datastore::keyset::MemoryStore;
+    use crate::datastore::record::Stage;
+    use futures::future::join_all;
+    use std::time::Duration;
+
+    fn spawn(dedupe: DedupeConfig) -> (Datastore, JoinHandle<AnyResult<()>>) {
+        Datastore::spawn(Box::new(MemoryStore::in_memory(dedupe)))
+    }
+
+    fn item(link: &str, title: &str) -> ItemRecord {
+        ItemRecord {
+            feed: "http://feed".to_string(),
+            link: link.to_string(),
+            title: Some(title.to_string()),
+            show: Some("the show".to_string()),
+            season: Some(1),
+            episode: Some(2),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn every_request_gets_its_own_answer() {
+        let (datastore, _) = spawn(DedupeConfig::default());
+        let checks = (0..50).map(|n| {
+            let datastore = datastore.clone();
+            async move {
+                let link = format!("http://a/{}", n);
+                let batch = vec![
+                    item(&link, "The.Show.S01E02"),
+                    item(&format!("{}?token=x", link), "The.Show.S01E02"),
+                ];
+                (n, datastore.check(batch).await.unwrap())
+            }
+        });
+        for (n, seen) in join_all(checks).await {
+            assert_eq!(seen, [false, true], "request {}", n);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_from_one_task_are_served_in_order() {
+        let (datastore, _) = spawn(DedupeConfig::default());
+        let link = "http://a/1";
+        for stage in [Stage::Grabbed, Stage::Added, Stage::Downloading] {
+            let event = LifecycleEvent {
+                hash: Some("ab".repeat(20)),
+                ..LifecycleEvent::new(link, stage)
+            };
+            datastore.add_event(event).await.unwrap();
+        }
+        let tracked = datastore.tracked().await.unwrap();
+        assert_eq!(tracked.len(), 1);
+        assert_eq!(tracked[0].stage, Stage::Downloading);
+    }
+
+    #[tokio::test]
+    async fn batches_are_checked_and_confirmed_together() {
+        let (datastore, _) = spawn(DedupeConfig {
+            key: DedupeKey::Episode,
+            upgrades: false,
+        });
+        let batch = vec![
+            item("http://a/1", "The.Show.S01E02.720p"),
+            item("http://a/2", "The.Show.S01E02.1080p"),
+        ];
+        assert_eq!(datastore.check(batch.clone()).await.unwrap(), [false, true]);
+        datastore
+            .confirm_all(vec![(batch[0].clone(), Status::Submitted)])
+            .await
+            .unwrap();
+        let again = vec![item("http://a/3", "The.Show.S01E02.480p")];
+        assert_eq!(datastore.check(again).await.unwrap(), [true]);
+    }
+
+    #[tokio::test]
+    async fn the_store_stops_once_every_handle_is_dropped() {
+        let (datastore, task) = spawn(DedupeConfig::default());
+        let clone = datastore.clone();
+        drop(datastore);
+        clone.tracked().await.unwrap();
+        drop(clone);
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("the store kept running")
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,488 @@
+use super::client::{
+    check, confirm, retain, save, Client, MemorySave, Restorable, RssSave, Savable, Seen,
+};
+use super::dedupe::{self, episode_key, is_upgrade, normalise_show, quality, seen_quality};
+use super::record::{ImportRecord, ItemRecord, LifecycleEvent, Status, TorrentRecord};
+use super::store::{Forget, Store};
+use crate::config::types::{DedupeConfig, DedupeKey, RetentionConfig};
+use crate::journal::client::now;
+use anyhow::{bail, Ok as AnyOk, Result as AnyResult};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// A store that only keeps the keys of seen items, written out by `Op`.
+/// Items waiting on Transmission are kept in memory, so they are sent again
+/// after a restart. Torrents, imports and lifecycle events are appended to
+/// `log`, when there is one.
+pub struct KeySetStore<Op> {
+    client: RefCell<Client>,
+    dedupe: DedupeConfig,
+    in_flight: RefCell<HashMap<String, ItemRecord>>,
+    torrents: RefCell<Vec<TorrentRecord>>,
+    imports: RefCell<Vec<ImportRecord>>,
+    events: RefCell<Vec<LifecycleEvent>>,
+    log: Option<PathBuf>,
+    /// Held for as long as the store is open by a process that writes the file.
+    _lock: Option<File>,
+    op: PhantomData<Op>,
+}
+
+/// One line of the log kept next to the JSON save file.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogEntry {
+    Torrent(TorrentRecord),
+    Import(ImportRecord),
+    Event(LifecycleEvent),
+}
+
+/// The original JSON save file.
+pub type JsonStore = KeySetStore<RssSave>;
+
+/// Nothing outlives the process, which is what tests want.
+pub type MemoryStore = KeySetStore<MemorySave>;
+
+impl<Op> KeySetStore<Op> {
+    fn new(client: Client, dedupe: DedupeConfig) -> Self {
+        Self {
+            client: RefCell::new(client),
+            dedupe,
+            in_flight: RefCell::new(HashMap::new()),
+            torrents: RefCell::new(vec![]),
+            imports: RefCell::new(vec![]),
+            events: RefCell::new(vec![]),
+            log: None,
+            _lock: None,
+            op: PhantomData,
+        }
+    }
+
+    fn append(&self, entry: LogEntry) -> AnyResult<()> {
+        if let Some(log) = &self.log {
+            let mut file = OpenOptions::new().create(true).append(true).open(log)?;
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        }
+        AnyOk(())
+    }
+
+    fn read_log(&self, log: &Path) -> AnyResult<()> {
+        if !log.is_file() {
+            return AnyOk(());
+        }
+        for line in BufReader::new(File::open(log)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<LogEntry>(&line) {
+                Ok(LogEntry::Torrent(torrent)) => self.torrents.borrow_mut().push(torrent),
+                Ok(LogEntry::Import(import)) => self.imports.borrow_mut().push(import),
+                Ok(LogEntry::Event(event)) => self.events.borrow_mut().push(event),
+                Err(err) => println!("skipping bad history log line: {:?}", err),
+            }
+        }
+        AnyOk(())
+    }
+}
+
+/// `file` with `.<suffix>` added to its name.
+fn sibling(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(format!(".{}", suffix));
+    PathBuf::from(name)
+}
+
+/// Each writer keeps the keys in memory and rewrites the file whole, so only
+/// one process at a time may open it to write.
+fn lock(file: &Path) -> AnyResult<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(sibling(file, "lock"))?;
+    match lock.try_lock() {
+        Ok(()) => AnyOk(lock),
+        Err(TryLockError::WouldBlock) => bail!(
+            "{:?} is in use, stop the daemon before changing the json history",
+            file
+        ),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+impl JsonStore {
+    /// Opens the save file for a process that changes it.
+    pub fn open(file: &Path, dedupe: DedupeConfig) -> AnyResult<Self> {
+        let lock = lock(file)?;
+        AnyOk(Self {
+            _lock: Some(lock),
+            ..Self::open_to_read(file, dedupe)?
+        })
+    }
+
+    /// Opens the save file without taking it from the daemon. Anything
+    /// written through this store may be overwritten by the daemon.
+    pub fn open_to_read(file: &Path, dedupe: DedupeConfig) -> AnyResult<Self> {
+        let mut client = Client::new(file.to_path_buf());
+        client.restore()?;
+        let log = sibling(file, "log");
+        let store = Self {
+            log: Some(log.clone()),
+            ..Self::new(client, dedupe)
+        };
+        store.read_log(&log)?;
+        AnyOk(store)
+    }
+}
+
+impl MemoryStore {
+    pub fn in_memory(dedupe: DedupeConfig) -> Self {
+        Self::new(Client::in_memory(), dedupe)
+    }
+}
+
+impl<Op: Savable<A = String>> KeySetStore<Op> {
+    /// Links saved before dedupe keys existed are still honoured. Every key
+    /// that matches is noted as seen in the record's feed.
+    fn check(&self, record: &ItemRecord) -> bool {
+        let client = &self.client;
+        let key = dedupe::key(&self.dedupe, record);
+        let matched: Vec<String> = [&record.link, &key]
+            .into_iter()
+            .filter(|key| check::<Op>(key, client))
+            .cloned()
+            .collect();
+        if !matched.is_empty() {
+            self.touch(&matched, record);
+            return true;
+        }
+        let identity = dedupe::identity(&self.dedupe, record);
+        let releases: Vec<(String, u8)> = match episode_key(record) {
+            Some(episode) if self.dedupe.key == DedupeKey::Episode => client
+                .borrow()
+                .keys()
+                .filter_map(|stored| Some((stored.clone(), seen_quality(stored, &episode)?)))
+                .collect(),
+            _ => vec![],
+        };
+        let seen = releases.iter().map(|(_, quality)| *quality).max();
+        let in_flight = self
+            .in_flight
+            .borrow()
+            .values()
+            .filter(|other| other.link != record.link)
+            .filter(|other| dedupe::identity(&self.dedupe, other) == identity)
+            .map(|other| quality(other.title.as_deref().unwrap_or_default()))
+            .max();
+        if let Some(best) = seen.max(in_flight) {
+            if !is_upgrade(&self.dedupe, record, best) {
+                let matched: Vec<String> = releases.into_iter().map(|(key, _)| key).collect();
+                self.touch(&matched, record);
+                return true;
+            }
+        }
+        self.in_flight
+            .borrow_mut()
+            .insert(record.link.clone(), record.clone());
+        false
+    }
+
+    fn confirm(&self, record: &ItemRecord, status: Status) -> AnyResult<()> {
+        self.in_flight.borrow_mut().remove(&record.link);
+        if status.is_seen() {
+            let key = dedupe::key(&self.dedupe, record);
+            self.client.borrow_mut().touch(&key, &record.feed, now());
+            confirm::<Op>(&key, &self.client)?;
+        }
+        AnyOk(())
+    }
+
+    fn touch(&self, keys: &[String], record: &ItemRecord) {
+        let mut client = self.client.borrow_mut();
+        for key in keys {
+            client.touch(key, &record.feed, now());
+        }
+    }
+
+    /// The keys retention drops, by the same rules as the sqlite history.
+    fn expired(&self, retention: &RetentionConfig, now: u64) -> HashSet<String> {
+        let client = self.client.borrow();
+        let polls = client.polls();
+        let outside_window = |seen: &Seen| {
+            polls
+                .get(&seen.feed)
+                .is_none_or(|started| *started > seen.last_seen)
+        };
+        let mut expired = HashSet::new();
+        if let Some(days) = retention.days {
+            let cutoff = now.saturating_sub(days * 24 * 60 * 60);
+            expired.extend(
+                client
+                    .seen()
+                    .iter()
+                    .filter(|(_, seen)| seen.last_seen < cutoff && outside_window(seen))
+                    .map(|(key, _)| key.clone()),
+            );
+        }
+        if let Some(per_feed) = retention.per_feed {
+            let mut feeds: HashMap<&str, Vec<(&String, &Seen)>> = HashMap::new();
+            for (key, seen) in client.seen() {
+                feeds.entry(&seen.feed).or_default().push((key, seen));
+            }
+            for mut keys in feeds.into_values() {
+                keys.sort_by(|a, b| b.1.last_seen.cmp(&a.1.last_seen).then(b.0.cmp(a.0)));
+                expired.extend(
+                    keys.into_iter()
+                        .skip(per_feed)
+                        .filter(|(_, seen)| outside_window(seen))
+                        .map(|(key, _)| key.clone()),
+                );
+            }
+        }
+        expired
+    }
+}
+
+impl<Op: Savable<A = String>> Store for KeySetStore<Op> {
+    fn check_all(&self, records: &[ItemRecord]) -> AnyResult<Vec<bool>> {
+        let seen: Vec<bool> = records.iter().map(|r| self.check(r)).collect();
+        if seen.contains(&true) {
+            save::<Op>(&self.client)?;
+        }
+        AnyOk(seen)
+    }
+
+    fn confirm_all(&self, confirmed: &[(ItemRecord, Status)]) -> AnyResult<()> {
+        confirmed
+            .iter()
+            .try_for_each(|(record, status)| self.confirm(record, *status))
+    }
+
+    /// Only keys are kept, so items come back with just a link.
+    fn items(&self) -> AnyResult<Vec<ItemRecord>> {
+        AnyOk(
+            self.client
+                .borrow()
+                .keys()
+                .map(|key| ItemRecord::from_link(key))
+                .collect(),
+        )
+    }
+
+    fn polled(&self, feed: &str, started: u64) -> AnyResult<()> {
+        self.client.borrow_mut().polled(feed, started);
+        save::<Op>(&self.client)
+    }
+
+    /// Retention goes by when each key's item was last in its feed. Keys
+    /// from before that was kept count as seen at the upgrade.
+    fn prune(&self, retention: &RetentionConfig) -> AnyResult<usize> {
+        let expired = self.expired(retention, now());
+        if expired.is_empty() {
+            return AnyOk(0);
+        }
+        retain::<Op>(&self.client, |key| !expired.contains(key))
+    }
+
+    /// Only seen items mean anything to a key set.
+    fn import_items(&self, records: &[ItemRecord]) -> AnyResult<usize> {
+        let mut added = 0;
+        for record in records.iter().filter(|record| record.status.is_seen()) {
+            let key = dedupe::key(&self.dedupe, record);
+            if !check::<Op>(&key, &self.client) {
+                let at = record.grabbed_at;
+                self.client.borrow_mut().touch(&key, &record.feed, at);
+                confirm::<Op>(&key, &self.client)?;
+                added += 1;
+            }
+        }
+        AnyOk(added)
+    }
+
+    fn forget(&self, forget: &Forget) -> AnyResult<usize> {
+        match forget {
+            Forget::Link(link) => {
+                let stripped = dedupe::strip_query(link);
+                let removed = retain::<Op>(&self.client, |key| key != link && key != stripped)?;
+                if removed == 0 && self.dedupe.key != DedupeKey::Link {
+                    bail!(
+                        "the json history keeps {} keys rather than links, so {} cannot be found in it",
+                        format!("{:?}", self.dedupe.key).to_lowercase(),
+                        link
+                    )
+                }
+                AnyOk(removed)
+            }
+            Forget::Show(show) => {
+                // Only `episode` dedupe keys carry the show name.
+                let prefix = format!("{} s", normalise_show(show));
+                retain::<Op>(&self.client, |key| !key.starts_with(&prefix))
+            }
+            Forget::Range { .. } => {
+                bail!("the json history has no grab times, use the sqlite backend")
+            }
+        }
+    }
+
+    fn add_torrent(&self, torrent: &TorrentRecord) -> AnyResult<()> {
+        self.append(LogEntry::Torrent(torrent.clone()))?;
+        self.torrents.borrow_mut().push(torrent.clone());
+        AnyOk(())
+    }
+
+    fn find_torrent(&self, name: &str) -> AnyResult<Option<TorrentRecord>> {
+        AnyOk(
+            self.torrents
+                .borrow()
+                .iter()
+                .rev()
+                .find(|torrent| torrent.name.as_deref() == Some(name))
+                .cloned(),
+        )
+    }
+
+    fn add_import(&self, import: &ImportRecord) -> AnyResult<()> {
+        self.append(LogEntry::Import(import.clone()))?;
+        self.imports.borrow_mut().push(import.clone());
+        AnyOk(())
+    }
+
+    fn add_event(&self, event: &LifecycleEvent) -> AnyResult<()> {
+        self.append(LogEntry::Event(event.clone()))?;
+        self.events.borrow_mut().push(event.clone());
+        AnyOk(())
+    }
+
+    fn events(&self, link: &str) -> AnyResult<Vec<LifecycleEvent>> {
+        AnyOk(
+            self.events
+                .borrow()
+                .iter()
+                .filter(|event| event.link == link)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn tracked(&self) -> AnyResult<Vec<LifecycleEvent>> {
+        let mut latest: Vec<LifecycleEvent> = vec![];
+        for event in self.events.borrow().iter() {
+            let mut event = event.clone();
+            match latest.iter_mut().find(|other| other.link == event.link) {
+                Some(other) => {
+                    event.torrent_id = event.torrent_id.or(other.torrent_id);
+                    event.hash = event.hash.take().or(other.hash.take());
+                    *other = event;
+                }
+                None => latest.push(event),
+            }
+        }
+        latest.retain(|event| event.stage.in_transmission());
+        AnyOk(latest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    fn by(key: DedupeKey) -> DedupeConfig {
+        DedupeConfig {
+            key,
+            upgrades: false,
+        }
+    }
+
+    #[test]
+    fn only_one_process_may_write_the_save_file() {
+        let dir = ScratchDir::new("keyset-lock");
+        let file = dir.join("save");
+        let daemon = JsonStore::open(&file, by(DedupeKey::Link)).unwrap();
+        let error = JsonStore::open(&file, by(DedupeKey::Link)).err().unwrap();
+        assert!(error.to_string().contains("stop the daemon"), "{}", error);
+        assert!(JsonStore::open_to_read(&file, by(DedupeKey::Link)).is_ok());
+
+        drop(daemon);
+        assert!(JsonStore::open(&file, by(DedupeKey::Link)).is_ok());
+    }
+
+    #[test]
+    fn forgetting_a_link_says_when_keys_are_not_links() {
+        let store = MemoryStore::in_memory(by(DedupeKey::Episode));
+        let record = ItemRecord {
+            link: "http://a/1".to_string(),
+            show: Some("the show".to_string()),
+            season: Some(1),
+            episode: Some(2),
+            status: Status::Submitted,
+            ..Default::default()
+        };
+        store.import_items(&[record]).unwrap();
+        let error = store
+            .forget(&Forget::Link("http://a/1".to_string()))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("episode keys"), "{}", error);
+
+        let store = MemoryStore::in_memory(by(DedupeKey::Link));
+        assert_eq!(
+            store
+                .forget(&Forget::Link("http://a/1".to_string()))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn retention_keeps_the_newest_per_feed_across_restarts() {
+        let dir = ScratchDir::new("keyset-retention");
+        let file = dir.join("save");
+        let links = |store: &JsonStore| -> Vec<String> {
+            let mut links: Vec<String> = store
+                .items()
+                .unwrap()
+                .into_iter()
+                .map(|item| item.link)
+                .collect();
+            links.sort();
+            links
+        };
+        let store = JsonStore::open(&file, by(DedupeKey::Link)).unwrap();
+        let records: Vec<ItemRecord> = [
+            ("http://a/1", 300),
+            ("http://a/2", 200),
+            ("http://a/3", 100),
+        ]
+        .into_iter()
+        .map(|(link, ago)| ItemRecord {
+            feed: "http://feed".to_string(),
+            link: link.to_string(),
+            grabbed_at: now() - ago,
+            status: Status::Submitted,
+            ..Default::default()
+        })
+        .collect();
+        store.import_items(&records).unwrap();
+        store.polled("http://feed", now()).unwrap();
+        drop(store);
+
+        let store = JsonStore::open(&file, by(DedupeKey::Link)).unwrap();
+        let retention = RetentionConfig {
+            per_feed: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(store.prune(&retention).unwrap(), 1);
+        assert_eq!(links(&store), ["http://a/2", "http://a/3"]);
+        drop(store);
+
+        let store = JsonStore::open(&file, by(DedupeKey::Link)).unwrap();
+        assert_eq!(links(&store), ["http://a/2", "http://a/3"]);
+        assert_eq!(store.prune(&retention).unwrap(), 0);
+    }
+}
@@ -1 +1,8 @@
 pub mod client;
+pub mod dedupe;
+pub mod handle;
+pub mod keyset;
+pub mod record;
+pub mod sqlite;
+pub mod store;
+pub mod transfer;
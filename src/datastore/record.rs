@@ -0,0 +1,328 @@
+use super::dedupe::normalise_show;
+use crate::config::path_functions::title_info;
+use crate::journal::client::now;
+use anyhow::{bail, Context, Result as AnyResult};
+use regex::Regex;
+use rss::Item;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Where an item is in being handed to Transmission. Only `Submitted` and
+/// `Rejected` count as seen; the others are tried again on the next poll.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Sent to the RPC task, no answer yet.
+    #[default]
+    Pending,
+    /// Transmission accepted it.
+    Submitted,
+    /// Transmission could not be reached.
+    Failed,
+    /// Transmission answered but refused it, e.g. a broken torrent file.
+    Rejected,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Pending => "pending",
+            Status::Submitted => "submitted",
+            Status::Failed => "failed",
+            Status::Rejected => "rejected",
+        }
+    }
+    /// Rows written before statuses existed say `grabbed`, which meant submitted.
+    pub fn parse(status: &str) -> Self {
+        match status {
+            "pending" => Status::Pending,
+            "failed" => Status::Failed,
+            "rejected" => Status::Rejected,
+            _ => Status::Submitted,
+        }
+    }
+    pub fn is_seen(&self) -> bool {
+        matches!(self, Status::Submitted | Status::Rejected)
+    }
+}
+
+/// One RSS item the watcher has come across, with what could be parsed from it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ItemRecord {
+    pub feed: String,
+    pub guid: Option<String>,
+    pub link: String,
+    pub title: Option<String>,
+    pub show: Option<String>,
+    pub season: Option<i8>,
+    pub episode: Option<u16>,
+    pub infohash: Option<String>,
+    pub pub_date: Option<String>,
+    pub grabbed_at: u64,
+    pub status: Status,
+}
+
+impl ItemRecord {
+    /// Fails for items without a link, as there is nothing to grab. A title
+    /// the name heuristics make nothing of just leaves the show unknown.
+    pub fn from_item(feed: &str, item: &Item) -> AnyResult<Self> {
+        let link = item.link().context("the item has no link")?.trim();
+        if link.is_empty() {
+            bail!("the item's link is empty")
+        }
+        let link = link.to_string();
+        let release = item
+            .title()
+            .and_then(title_info)
+            .filter(|release| !release.movie);
+        Ok(Self {
+            feed: feed.to_string(),
+            guid: item.guid().map(|guid| guid.value().to_string()),
+            infohash: infohash(item),
+            title: item.title().map(String::from),
+            show: release
+                .as_ref()
+                .and_then(|release| release.show.as_deref())
+                .map(normalise_show),
+            season: release.as_ref().and_then(|release| release.season),
+            episode: release.as_ref().and_then(|release| release.episode),
+            pub_date: item.pub_date().map(String::from),
+            grabbed_at: now(),
+            status: Status::Pending,
+            link,
+        })
+    }
+
+    /// What older save files knew about an item: its link and nothing else.
+    pub fn from_link(link: &str) -> Self {
+        Self {
+            link: link.to_string(),
+            grabbed_at: now(),
+            status: Status::Submitted,
+            ..Default::default()
+        }
+    }
+}
+
+/// From a torznab `infohash` attribute, or a magnet link in the link or enclosure.
+fn infohash(item: &Item) -> Option<String> {
+    let torznab = item
+        .extensions()
+        .get("torznab")
+        .and_then(|ext| ext.get("attr"))
+        .into_iter()
+        .flatten()
+        .find(|attr| attr.attrs().get("name").map(String::as_str) == Some("infohash"))
+        .and_then(|attr| attr.attrs().get("value").cloned());
+    let magnet = Regex::new(r"(?i)xt=urn:btih:([a-z0-9]{32,40})").unwrap();
+    torznab
+        .or_else(|| {
+            [item.link(), item.enclosure().map(|e| e.url())]
+                .into_iter()
+                .flatten()
+                .find_map(|url| Some(magnet.captures(url)?.get(1)?.as_str().to_string()))
+        })
+        .map(|hash| hash.to_lowercase())
+}
+
+/// A torrent Transmission took on for an RSS item.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TorrentRecord {
+    pub link: String,
+    pub torrent_id: Option<i64>,
+    pub hash: Option<String>,
+    /// Transmission's name for it, which is also the name it is downloaded under.
+    pub name: Option<String>,
+    pub added_at: u64,
+}
+
+/// Something `copy_file` placed in the library.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportRecord {
+    pub torrent: String,
+    /// The RSS link it came from, when the torrent is known.
+    pub link: Option<String>,
+    #[serde(with = "crate::config::raw_path")]
+    pub source: PathBuf,
+    #[serde(with = "crate::config::raw_path")]
+    pub destination: PathBuf,
+    pub imported_at: u64,
+}
+
+/// How far an item has come, from the feed to the library.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// Picked from the feed and sent to Transmission.
+    Grabbed,
+    /// Transmission made a torrent of it.
+    Added,
+    Downloading,
+    Completed,
+    /// `copy_file` put it in the library.
+    Imported,
+    /// The torrent is gone from Transmission.
+    Removed,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Grabbed => "grabbed",
+            Stage::Added => "added",
+            Stage::Downloading => "downloading",
+            Stage::Completed => "completed",
+            Stage::Imported => "imported",
+            Stage::Removed => "removed",
+        }
+    }
+    pub fn parse(stage: &str) -> Option<Self> {
+        match stage {
+            "grabbed" => Some(Stage::Grabbed),
+            "added" => Some(Stage::Added),
+            "downloading" => Some(Stage::Downloading),
+            "completed" => Some(Stage::Completed),
+            "imported" => Some(Stage::Imported),
+            "removed" => Some(Stage::Removed),
+            _ => None,
+        }
+    }
+    /// Whether Transmission should still have the torrent.
+    pub fn in_transmission(&self) -> bool {
+        matches!(
+            self,
+            Stage::Added | Stage::Downloading | Stage::Completed | Stage::Imported
+        )
+    }
+}
+
+/// One step in an item's life, keyed by its RSS link.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LifecycleEvent {
+    pub link: String,
+    pub stage: Stage,
+    pub at: u64,
+    pub torrent_id: Option<i64>,
+    pub hash: Option<String>,
+    /// Where `copy_file` put it, for `Imported`.
+    #[serde(default, with = "crate::config::raw_path::option")]
+    pub path: Option<PathBuf>,
+}
+
+impl LifecycleEvent {
+    pub fn new(link: &str, stage: Stage) -> Self {
+        Self {
+            link: link.to_string(),
+            stage,
+            at: now(),
+            torrent_id: None,
+            hash: None,
+            path: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rss::Channel;
+
+    /// The items of a feed with the given `<item>` bodies.
+    fn items(bodies: &[&str]) -> Vec<Item> {
+        let xml = format!(
+            r#"<rss version="2.0" xmlns:torznab="http://torznab.com/schemas/2015/feed"><channel><title>t</title><link>l</link><description>d</description>{}</channel></rss>"#,
+            bodies
+                .iter()
+                .map(|body| format!("<item>{}</item>", body))
+                .collect::<String>()
+        );
+        Channel::read_from(xml.as_bytes()).unwrap().items().to_vec()
+    }
+
+    #[test]
+    fn episodes_are_parsed_from_the_title() {
+        let [item] =
+            &items(&["<title>The.Show.S02E03.720p</title><link>http://x/1</link><guid>g1</guid>"])
+                [..]
+        else {
+            panic!()
+        };
+        let record = ItemRecord::from_item("http://feed", item).unwrap();
+        assert_eq!(record.feed, "http://feed");
+        assert_eq!(record.link, "http://x/1");
+        assert_eq!(record.guid.as_deref(), Some("g1"));
+        assert_eq!(record.show.as_deref(), Some("the show"));
+        assert_eq!(record.season, Some(2));
+        assert_eq!(record.episode, Some(3));
+        assert_eq!(record.status, Status::Pending);
+    }
+
+    #[test]
+    fn odd_titles_and_movies_parse_without_a_show() {
+        let parsed = items(&[
+            "<title>Some.Movie.2020</title><link>http://x/1</link>",
+            "<title>\u{5de8}\u{4eba}\u{2013}S04E01</title><link>http://x/2</link>",
+            "<title>S01E01</title><link>http://x/3</link>",
+            "<title>No link S01E01</title>",
+            "<title>Empty link S01E01</title><link> </link>",
+            "<title>The Show / S01E02</title><link>http://x/6</link>",
+            "<title>..</title><link>http://x/7</link>",
+        ]);
+        let records: Vec<Option<ItemRecord>> = parsed
+            .iter()
+            .map(|item| ItemRecord::from_item("http://feed", item).ok())
+            .collect();
+        let movie = records[0].as_ref().unwrap();
+        assert_eq!((movie.show.as_ref(), movie.season), (None, None));
+        let cjk = records[1].as_ref().unwrap();
+        assert_eq!(cjk.show.as_deref(), Some("\u{5de8}\u{4eba}"));
+        assert_eq!(cjk.episode, Some(1));
+        let bare = records[2].as_ref().unwrap();
+        assert_eq!((bare.show.as_ref(), bare.season), (None, Some(1)));
+        assert!(records[3].is_none());
+        assert!(records[4].is_none());
+        let slashed = records[5].as_ref().unwrap();
+        assert_eq!(slashed.show.as_deref(), Some("the show"));
+        assert_eq!(slashed.episode, Some(2));
+        let dots = records[6].as_ref().unwrap();
+        assert_eq!((dots.show.as_ref(), dots.season), (None, None));
+    }
+
+    #[test]
+    fn infohashes_come_from_torznab_or_magnet_links() {
+        let hash = "0123456789ABCDEF0123456789ABCDEF01234567";
+        let parsed = items(&[
+            &format!(
+                r#"<title>a</title><link>http://x/1</link><torznab:attr name="infohash" value="{}"/>"#,
+                hash
+            ),
+            &format!(
+                "<title>b</title><link>magnet:?xt=urn:btih:{}&amp;dn=b</link>",
+                hash
+            ),
+            &format!(
+                r#"<title>c</title><link>http://x/3</link><enclosure url="magnet:?xt=urn:btih:{}" length="0" type="application/x-bittorrent"/>"#,
+                hash
+            ),
+            "<title>d</title><link>http://x/4</link>",
+        ]);
+        let hashes: Vec<Option<String>> = parsed.iter().map(infohash).collect();
+        let lower = Some(hash.to_lowercase());
+        assert_eq!(hashes, [lower.clone(), lower.clone(), lower, None]);
+    }
+
+    #[test]
+    fn statuses_round_trip_and_legacy_rows_count_as_submitted() {
+        for status in [
+            Status::Pending,
+            Status::Submitted,
+            Status::Failed,
+            Status::Rejected,
+        ] {
+            assert_eq!(Status::parse(status.as_str()), status);
+        }
+        assert_eq!(Status::parse("grabbed"), Status::Submitted);
+        assert!(Status::Rejected.is_seen());
+        assert!(!Status::Failed.is_seen());
+    }
+}
@@ -0,0 +1,925 @@
+use super::client::read_set;
+use super::dedupe::{is_upgrade, normalise_show, quality, strip_query};
+use super::record::{ImportRecord, ItemRecord, LifecycleEvent, Stage, Status, TorrentRecord};
+use super::store::{Forget, Store};
+use crate::config::raw_path;
+use crate::config::types::{DedupeConfig, DedupeKey, RetentionConfig};
+use crate::journal::client::now;
+use anyhow::{bail, Ok as AnyOk, Result as AnyResult};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use std::path::Path;
+use std::time::Duration;
+
+const ITEM_COLUMNS: &str =
+    "feed, guid, link, title, show, season, episode, infohash, pub_date, grabbed_at, status";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS items (
+    id INTEGER PRIMARY KEY,
+    feed TEXT NOT NULL,
+    guid TEXT,
+    link TEXT NOT NULL,
+    title TEXT,
+    show TEXT,
+    season INTEGER,
+    episode INTEGER,
+    infohash TEXT,
+    pub_date TEXT,
+    grabbed_at INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS items_link ON items (link);
+CREATE INDEX IF NOT EXISTS items_guid ON items (guid);
+CREATE INDEX IF NOT EXISTS items_infohash ON items (infohash);
+CREATE INDEX IF NOT EXISTS items_episode ON items (show, season, episode);
+CREATE INDEX IF NOT EXISTS items_feed ON items (feed, grabbed_at);
+CREATE TABLE IF NOT EXISTS polls (
+    feed TEXT PRIMARY KEY,
+    started INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS torrents (
+    id INTEGER PRIMARY KEY,
+    link TEXT NOT NULL,
+    torrent_id INTEGER,
+    hash TEXT,
+    name TEXT,
+    added_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS torrents_link ON torrents (link);
+CREATE INDEX IF NOT EXISTS torrents_name ON torrents (name);
+CREATE TABLE IF NOT EXISTS imports (
+    id INTEGER PRIMARY KEY,
+    torrent TEXT NOT NULL,
+    link TEXT,
+    source BLOB NOT NULL,
+    destination BLOB NOT NULL,
+    imported_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS imports_torrent ON imports (torrent);
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+";
+
+/// Ordered schema steps; the database's `user_version` says how many have run.
+/// Databases from before versioning start at 0, so every step has to cope
+/// with its change already being there.
+const MIGRATIONS: [fn(&SqliteStore) -> AnyResult<()>; 5] = [
+    SqliteStore::create_tables,
+    SqliteStore::add_link_key,
+    SqliteStore::add_last_seen,
+    SqliteStore::add_events,
+    SqliteStore::normalise_shows,
+];
+
+const EVENT_COLUMNS: &str = "link, stage, at, torrent_id, hash, path";
+
+/// How long a write waits for another process holding the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// History of grabbed RSS items in a SQLite database.
+pub struct SqliteStore {
+    connection: Connection,
+    dedupe: DedupeConfig,
+}
+
+impl SqliteStore {
+    /// Opens or creates the database. The first time, links from the old JSON
+    /// save file at `json` are imported. The daemon and the history commands
+    /// may have it open at once, so writers wait on each other for a while
+    /// and readers never block the daemon.
+    pub fn open(file: &Path, json: &Path, dedupe: DedupeConfig) -> AnyResult<Self> {
+        let connection = Connection::open(file)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        let store = Self { connection, dedupe };
+        store.migrate()?;
+        store.migrate_json(json)?;
+        AnyOk(store)
+    }
+
+    /// Runs whichever of `MIGRATIONS` the database has not had yet, each in
+    /// its own transaction. A database from a newer build is refused.
+    fn migrate(&self) -> AnyResult<()> {
+        let version = self
+            .connection
+            .query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))?
+            as usize;
+        if version > MIGRATIONS.len() {
+            bail!(
+                "schema version {} is newer than the supported {}",
+                version,
+                MIGRATIONS.len()
+            )
+        }
+        for (done, step) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = self.connection.unchecked_transaction()?;
+            step(self)?;
+            transaction.pragma_update(None, "user_version", (done + 1) as i64)?;
+            transaction.commit()?;
+        }
+        AnyOk(())
+    }
+
+    /// Deletes what is kept about links that are no longer items. Imports
+    /// of downloads that never came from a feed have no link and stay.
+    fn drop_orphans(&self) -> AnyResult<()> {
+        AnyOk(self.connection.execute_batch(
+            "DELETE FROM events WHERE link NOT IN (SELECT link FROM items);
+             DELETE FROM torrents WHERE link NOT IN (SELECT link FROM items);
+             DELETE FROM imports WHERE link IS NOT NULL AND link NOT IN (SELECT link FROM items);",
+        )?)
+    }
+
+    fn create_tables(&self) -> AnyResult<()> {
+        AnyOk(self.connection.execute_batch(SCHEMA)?)
+    }
+
+    fn has_column(&self, column: &str) -> AnyResult<bool> {
+        AnyOk(
+            self.connection
+                .prepare("SELECT 1 FROM pragma_table_info('items') WHERE name = ?1")?
+                .exists(params![column])?,
+        )
+    }
+
+    /// Databases from before dedupe keys get the stripped link column filled in.
+    fn add_link_key(&self) -> AnyResult<()> {
+        if self.has_column("link_key")? {
+            return AnyOk(());
+        }
+        self.connection.execute_batch(
+            "ALTER TABLE items ADD COLUMN link_key TEXT;
+             CREATE INDEX items_link_key ON items (link_key);",
+        )?;
+        let links = self
+            .connection
+            .prepare("SELECT id, link FROM items")?
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (id, link) in links {
+            self.connection.execute(
+                "UPDATE items SET link_key = ?1 WHERE id = ?2",
+                params![strip_query(&link), id],
+            )?;
+        }
+        AnyOk(())
+    }
+
+    /// When an item was last in a feed, which is what retention goes by.
+    /// Older databases start from the grab time.
+    fn add_last_seen(&self) -> AnyResult<()> {
+        if self.has_column("last_seen")? {
+            return AnyOk(());
+        }
+        self.connection.execute_batch(
+            "ALTER TABLE items ADD COLUMN last_seen INTEGER;
+             UPDATE items SET last_seen = grabbed_at;
+             CREATE INDEX items_last_seen ON items (feed, last_seen);",
+        )?;
+        AnyOk(())
+    }
+
+    /// Lifecycle events, started off from what the older tables already knew.
+    /// The backfill only runs when the table is new, so it is never doubled.
+    fn add_events(&self) -> AnyResult<()> {
+        let existed = self
+            .connection
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'events'")?
+            .exists([])?;
+        self.connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS events (
+                id INTEGER PRIMARY KEY,
+                link TEXT NOT NULL,
+                stage TEXT NOT NULL,
+                at INTEGER NOT NULL,
+                torrent_id INTEGER,
+                hash TEXT,
+                path BLOB
+            );
+            CREATE INDEX IF NOT EXISTS events_link ON events (link, id);",
+        )?;
+        if existed {
+            return AnyOk(());
+        }
+        self.connection.execute_batch(
+            "INSERT INTO events (link, stage, at)
+                SELECT link, 'grabbed', grabbed_at FROM items WHERE status = 'submitted';
+            INSERT INTO events (link, stage, at, torrent_id, hash)
+                SELECT link, 'added', added_at, torrent_id, hash FROM torrents ORDER BY id;
+            INSERT INTO events (link, stage, at, path)
+                SELECT link, 'imported', imported_at, destination FROM imports
+                WHERE link IS NOT NULL ORDER BY id;",
+        )?;
+        AnyOk(())
+    }
+
+    /// Shows used to be stored as parsed, e.g. `the.show`, so the same show
+    /// under another spelling did not dedupe.
+    fn normalise_shows(&self) -> AnyResult<()> {
+        let shows = self
+            .connection
+            .prepare("SELECT DISTINCT show FROM items WHERE show IS NOT NULL")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for show in shows {
+            self.connection.execute(
+                "UPDATE items SET show = ?2 WHERE show = ?1",
+                params![show, normalise_show(&show)],
+            )?;
+        }
+        AnyOk(())
+    }
+
+    fn migrate_json(&self, json: &Path) -> AnyResult<()> {
+        let done: Option<String> = self
+            .connection
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'json_migrated'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if done.is_some() {
+            return AnyOk(());
+        }
+        let links = if json.is_file() {
+            read_set(json)?
+        } else {
+            Default::default()
+        };
+        for link in &links {
+            self.insert(&ItemRecord::from_link(link))?;
+        }
+        self.connection.execute(
+            "INSERT INTO meta (key, value) VALUES ('json_migrated', ?1)",
+            params![json.to_string_lossy()],
+        )?;
+        if !links.is_empty() {
+            println!("imported {} links from {:?}", links.len(), json);
+        }
+        AnyOk(())
+    }
+
+    fn insert(&self, record: &ItemRecord) -> AnyResult<usize> {
+        AnyOk(self.connection.execute(
+            "INSERT OR IGNORE INTO items
+                (feed, guid, link, title, show, season, episode, infohash, pub_date, grabbed_at, status, link_key, last_seen)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?10)",
+            params![
+                record.feed,
+                record.guid,
+                record.link,
+                record.title,
+                record.show.as_deref().map(normalise_show),
+                record.season,
+                record.episode,
+                record.infohash,
+                record.pub_date,
+                record.grabbed_at as i64,
+                record.status.as_str(),
+                strip_query(&record.link),
+            ],
+        )?)
+    }
+
+    /// The `WHERE` clause finding items that share `record`'s dedupe key.
+    /// Items missing the configured field fall back to the stripped link.
+    fn same_item(&self, record: &ItemRecord) -> (&'static str, Vec<Value>) {
+        let link_key = || {
+            (
+                "link_key = ?2",
+                vec![Value::Text(strip_query(&record.link).to_string())],
+            )
+        };
+        match self.dedupe.key {
+            DedupeKey::Link => link_key(),
+            DedupeKey::Guid => match &record.guid {
+                Some(guid) => ("guid = ?2", vec![Value::Text(guid.clone())]),
+                None => link_key(),
+            },
+            DedupeKey::Infohash => match &record.infohash {
+                Some(hash) => ("infohash = ?2", vec![Value::Text(hash.clone())]),
+                None => link_key(),
+            },
+            DedupeKey::Episode => match (&record.show, record.season, record.episode) {
+                (Some(show), Some(season), Some(episode)) => (
+                    "show = ?2 AND season = ?3 AND episode = ?4",
+                    vec![
+                        Value::Text(normalise_show(show)),
+                        Value::Integer(season.into()),
+                        Value::Integer(episode.into()),
+                    ],
+                ),
+                _ => link_key(),
+            },
+        }
+    }
+
+    /// True if Transmission already has the item, or refused it, under the
+    /// configured dedupe key. Anything else is (re)recorded as pending and
+    /// false is returned so it gets sent.
+    fn check(&self, record: &ItemRecord) -> AnyResult<bool> {
+        let (clause, values) = self.same_item(record);
+        let sql = format!(
+            "SELECT link, title, status FROM items WHERE link = ?1 OR ({})",
+            clause
+        );
+        let mut values = values;
+        values.insert(0, Value::Text(record.link.clone()));
+        // Everything this item is deduped against has to outlive pruning for as
+        // long as the item is still in the feed.
+        self.connection.execute(
+            &format!(
+                "UPDATE items SET last_seen = {} WHERE link = ?1 OR ({})",
+                now(),
+                clause
+            ),
+            params_from_iter(values.iter()),
+        )?;
+        let matches = self
+            .connection
+            .prepare(&sql)?
+            .query_map(params_from_iter(values), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    Status::parse(&row.get::<_, String>(2)?),
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if matches
+            .iter()
+            .any(|(link, _, status)| *link == record.link && status.is_seen())
+        {
+            return AnyOk(true);
+        }
+        // Other releases count once they are seen, or while they are still on
+        // their way to Transmission, so one poll cannot grab an episode twice.
+        let taken = matches
+            .iter()
+            .filter(|(link, _, status)| {
+                status.is_seen() || (*link != record.link && *status == Status::Pending)
+            })
+            .map(|(_, title, _)| quality(title.as_deref().unwrap_or_default()))
+            .max();
+        if let Some(best) = taken {
+            if !is_upgrade(&self.dedupe, record, best) {
+                return AnyOk(true);
+            }
+            println!("{:?} is an upgrade, grabbing it again", record.title);
+        }
+
+        if matches.iter().any(|(link, _, _)| *link == record.link) {
+            self.confirm(&record.link, Status::Pending)?;
+        } else {
+            self.insert(&ItemRecord {
+                status: Status::Pending,
+                ..record.clone()
+            })?;
+        }
+        AnyOk(false)
+    }
+
+    /// Records what the RPC task made of an item.
+    fn confirm(&self, link: &str, status: Status) -> AnyResult<()> {
+        self.connection.execute(
+            "UPDATE items SET status = ?1 WHERE link = ?2",
+            params![status.as_str(), link],
+        )?;
+        AnyOk(())
+    }
+
+    fn items_where(&self, clause: &str, values: Vec<Value>) -> AnyResult<Vec<ItemRecord>> {
+        let sql = format!(
+            "SELECT {} FROM items WHERE {} ORDER BY grabbed_at, id",
+            ITEM_COLUMNS, clause
+        );
+        let items = self
+            .connection
+            .prepare(&sql)?
+            .query_map(params_from_iter(values), item_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        AnyOk(items)
+    }
+}
+
+impl Store for SqliteStore {
+    /// Called before a poll checks its items, so `prune` knows which items
+    /// are in the current window.
+    fn polled(&self, feed: &str, started: u64) -> AnyResult<()> {
+        self.connection.execute(
+            "INSERT INTO polls (feed, started) VALUES (?1, ?2)
+             ON CONFLICT (feed) DO UPDATE SET started = excluded.started",
+            params![feed, started as i64],
+        )?;
+        AnyOk(())
+    }
+
+    /// Deletes items the retention policy no longer wants. Items seen since
+    /// their own feed's latest poll started are kept, as dropping them would
+    /// get them grabbed again. Feeds that are no longer polled keep nothing
+    /// else, so one stale feed cannot hold back pruning of the others.
+    /// The events, torrents and imports of deleted items go with them.
+    fn prune(&self, retention: &RetentionConfig) -> AnyResult<usize> {
+        let outside_window = "NOT EXISTS (
+            SELECT 1 FROM polls WHERE polls.feed = items.feed AND polls.started <= items.last_seen
+        )";
+        let transaction = self.connection.unchecked_transaction()?;
+        let mut removed = 0;
+        if let Some(days) = retention.days {
+            removed += self.connection.execute(
+                &format!(
+                    "DELETE FROM items WHERE last_seen < ?1 AND {}",
+                    outside_window
+                ),
+                params![now().saturating_sub(days * 24 * 60 * 60) as i64],
+            )?;
+        }
+        if let Some(per_feed) = retention.per_feed {
+            removed += self.connection.execute(
+                &format!(
+                    "DELETE FROM items WHERE {} AND id IN (
+                        SELECT id FROM (
+                            SELECT id, ROW_NUMBER() OVER (
+                                PARTITION BY feed ORDER BY last_seen DESC, id DESC
+                            ) AS newest
+                            FROM items
+                        ) WHERE newest > ?1
+                    )",
+                    outside_window
+                ),
+                params![per_feed as i64],
+            )?;
+        }
+        if removed > 0 {
+            self.drop_orphans()?;
+        }
+        transaction.commit()?;
+        AnyOk(removed)
+    }
+
+    /// Checks several items in one transaction, in order, so later items
+    /// see earlier ones as pending.
+    fn check_all(&self, records: &[ItemRecord]) -> AnyResult<Vec<bool>> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let seen = records
+            .iter()
+            .map(|record| self.check(record))
+            .collect::<AnyResult<Vec<_>>>()?;
+        transaction.commit()?;
+        AnyOk(seen)
+    }
+
+    fn confirm_all(&self, confirmed: &[(ItemRecord, Status)]) -> AnyResult<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        for (record, status) in confirmed {
+            self.confirm(&record.link, *status)?;
+        }
+        transaction.commit()?;
+        AnyOk(())
+    }
+
+    fn add_torrent(&self, torrent: &TorrentRecord) -> AnyResult<()> {
+        self.connection.execute(
+            "INSERT INTO torrents (link, torrent_id, hash, name, added_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                torrent.link,
+                torrent.torrent_id,
+                torrent.hash,
+                torrent.name,
+                torrent.added_at as i64,
+            ],
+        )?;
+        AnyOk(())
+    }
+
+    /// The newest torrent Transmission gave this name.
+    fn find_torrent(&self, name: &str) -> AnyResult<Option<TorrentRecord>> {
+        AnyOk(
+            self.connection
+                .query_row(
+                    "SELECT link, torrent_id, hash, name, added_at FROM torrents
+                 WHERE name = ?1 ORDER BY added_at DESC, id DESC LIMIT 1",
+                    params![name],
+                    |row| {
+                        Ok(TorrentRecord {
+                            link: row.get(0)?,
+                            torrent_id: row.get(1)?,
+                            hash: row.get(2)?,
+                            name: row.get(3)?,
+                            added_at: row.get::<_, i64>(4)? as u64,
+                        })
+                    },
+                )
+                .optional()?,
+        )
+    }
+
+    /// Paths are stored as raw bytes, so names that are not UTF-8 survive.
+    fn add_import(&self, import: &ImportRecord) -> AnyResult<()> {
+        self.connection.execute(
+            "INSERT INTO imports (torrent, link, source, destination, imported_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                import.torrent,
+                import.link,
+                raw_path::to_bytes(&import.source),
+                raw_path::to_bytes(&import.destination),
+                import.imported_at as i64,
+            ],
+        )?;
+        AnyOk(())
+    }
+
+    fn add_event(&self, event: &LifecycleEvent) -> AnyResult<()> {
+        self.connection.execute(
+            &format!(
+                "INSERT INTO events ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                EVENT_COLUMNS
+            ),
+            params![
+                event.link,
+                event.stage.as_str(),
+                event.at as i64,
+                event.torrent_id,
+                event.hash,
+                event.path.as_deref().map(raw_path::to_bytes),
+            ],
+        )?;
+        AnyOk(())
+    }
+
+    /// Everything that happened to the item with this link, oldest first.
+    fn events(&self, link: &str) -> AnyResult<Vec<LifecycleEvent>> {
+        let events = self
+            .connection
+            .prepare(&format!(
+                "SELECT {} FROM events WHERE link = ?1 ORDER BY id",
+                EVENT_COLUMNS
+            ))?
+            .query_map(params![link], event_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        AnyOk(events.into_iter().flatten().collect())
+    }
+
+    /// The latest event of every item Transmission should still have, with
+    /// the torrent's id and hash from whichever event last had them.
+    fn tracked(&self) -> AnyResult<Vec<LifecycleEvent>> {
+        let events = self
+            .connection
+            .prepare(
+                "SELECT link, stage, at,
+                    (SELECT torrent_id FROM events t WHERE t.link = e.link
+                        AND t.torrent_id IS NOT NULL ORDER BY t.id DESC LIMIT 1),
+                    (SELECT hash FROM events h WHERE h.link = e.link
+                        AND h.hash IS NOT NULL ORDER BY h.id DESC LIMIT 1),
+                    path
+                 FROM events e
+                 WHERE id = (SELECT MAX(id) FROM events l WHERE l.link = e.link)
+                 ORDER BY id",
+            )?
+            .query_map([], event_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        AnyOk(
+            events
+                .into_iter()
+                .flatten()
+                .filter(|event| event.stage.in_transmission())
+                .collect(),
+        )
+    }
+
+    fn items(&self) -> AnyResult<Vec<ItemRecord>> {
+        self.items_where("1 = 1", vec![])
+    }
+
+    /// Items whose title, link or show contains `text`, ignoring case.
+    fn search(&self, text: &str) -> AnyResult<Vec<ItemRecord>> {
+        self.items_where(
+            "title LIKE ?1 OR link LIKE ?1 OR show LIKE ?1",
+            vec![Value::Text(format!("%{}%", text))],
+        )
+    }
+
+    /// Adds items that are not already known by link, returning how many were new.
+    fn import_items(&self, records: &[ItemRecord]) -> AnyResult<usize> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let mut added = 0;
+        for record in records {
+            added += self.insert(record)?;
+        }
+        transaction.commit()?;
+        AnyOk(added)
+    }
+
+    /// The events, torrents and imports of forgotten items go with them, so
+    /// their torrents are no longer tracked either.
+    fn forget(&self, forget: &Forget) -> AnyResult<usize> {
+        let (condition, values) = match forget {
+            Forget::Link(link) => (
+                "link = ?1 OR link_key = ?2",
+                vec![
+                    Value::Text(link.clone()),
+                    Value::Text(strip_query(link).to_string()),
+                ],
+            ),
+            Forget::Show(show) => ("show = ?1", vec![Value::Text(normalise_show(show))]),
+            Forget::Range { from, to } => (
+                "grabbed_at BETWEEN ?1 AND ?2",
+                vec![Value::Integer(*from as i64), Value::Integer(*to as i64)],
+            ),
+        };
+        let transaction = self.connection.unchecked_transaction()?;
+        let links = self
+            .connection
+            .prepare(&format!("SELECT link FROM items WHERE {}", condition))?
+            .query_map(params_from_iter(&values), |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let removed = self.connection.execute(
+            &format!("DELETE FROM items WHERE {}", condition),
+            params_from_iter(&values),
+        )?;
+        for link in &links {
+            for table in ["events", "torrents", "imports"] {
+                self.connection.execute(
+                    &format!("DELETE FROM {} WHERE link = ?1", table),
+                    params![link],
+                )?;
+            }
+        }
+        transaction.commit()?;
+        AnyOk(removed)
+    }
+}
+
+fn item_from_row(row: &Row) -> rusqlite::Result<ItemRecord> {
+    Ok(ItemRecord {
+        feed: row.get(0)?,
+        guid: row.get(1)?,
+        link: row.get(2)?,
+        title: row.get(3)?,
+        show: row.get(4)?,
+        season: row.get(5)?,
+        episode: row.get(6)?,
+        infohash: row.get(7)?,
+        pub_date: row.get(8)?,
+        grabbed_at: row.get::<_, i64>(9)? as u64,
+        status: Status::parse(&row.get::<_, String>(10)?),
+    })
+}
+
+/// `None` for stages this build does not know.
+fn event_from_row(row: &Row) -> rusqlite::Result<Option<LifecycleEvent>> {
+    let Some(stage) = Stage::parse(&row.get::<_, String>(1)?) else {
+        return Ok(None);
+    };
+    Ok(Some(LifecycleEvent {
+        link: row.get(0)?,
+        stage,
+        at: row.get::<_, i64>(2)? as u64,
+        torrent_id: row.get(3)?,
+        hash: row.get(4)?,
+        path: row.get::<_, Option<Vec<u8>>>(5)?.map(raw_path::from_bytes),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+    use std::path::PathBuf;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn open(dir: &ScratchDir) -> SqliteStore {
+        SqliteStore::open(
+            &dir.join("history.db"),
+            &dir.join("save"),
+            DedupeConfig::default(),
+        )
+        .unwrap()
+    }
+
+    /// A submitted item last seen `ago` seconds before now.
+    fn seen(store: &SqliteStore, feed: &str, link: &str, ago: u64) {
+        let record = ItemRecord {
+            feed: feed.to_string(),
+            link: link.to_string(),
+            grabbed_at: now() - ago,
+            status: Status::Submitted,
+            ..Default::default()
+        };
+        store.import_items(&[record]).unwrap();
+    }
+
+    fn links(store: &SqliteStore) -> Vec<String> {
+        let mut links: Vec<String> = store
+            .items()
+            .unwrap()
+            .into_iter()
+            .map(|item| item.link)
+            .collect();
+        links.sort();
+        links
+    }
+
+    fn retention(days: Option<u64>, per_feed: Option<usize>) -> RetentionConfig {
+        RetentionConfig {
+            days,
+            per_feed,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn a_second_connection_waits_for_a_writer() {
+        let dir = ScratchDir::new("sqlite-busy");
+        let daemon = open(&dir);
+        let admin = open(&dir);
+        let mode: String = admin
+            .connection
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        daemon.connection.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            daemon.connection.execute_batch("COMMIT").unwrap();
+        });
+        seen(&admin, "http://feed", "http://feed/1", 0);
+        writer.join().unwrap();
+        assert_eq!(links(&admin), ["http://feed/1"]);
+    }
+
+    fn count(store: &SqliteStore, table: &str) -> i64 {
+        store
+            .connection
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn migrations_can_run_again_over_a_populated_database() {
+        let dir = ScratchDir::new("sqlite-migrate-twice");
+        let store = open(&dir);
+        seen(&store, "http://feed", "http://feed/1?token=a", 0);
+        store
+            .add_event(&LifecycleEvent::new(
+                "http://feed/1?token=a",
+                Stage::Grabbed,
+            ))
+            .unwrap();
+        let events = count(&store, "events");
+
+        store
+            .connection
+            .pragma_update(None, "user_version", 0)
+            .unwrap();
+        store.migrate().unwrap();
+        for step in MIGRATIONS {
+            step(&store).unwrap();
+        }
+
+        assert_eq!(count(&store, "events"), events);
+        assert_eq!(links(&store), ["http://feed/1?token=a"]);
+        let version: i64 = store
+            .connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn pruned_items_take_their_events_torrents_and_imports_along() {
+        let dir = ScratchDir::new("sqlite-prune-dependents");
+        let store = open(&dir);
+        store.polled("http://live", now() - 60).unwrap();
+        seen(&store, "http://live", "http://live/old", 10 * DAY);
+        seen(&store, "http://live", "http://live/current", 30);
+        for link in ["http://live/old", "http://live/current"] {
+            store
+                .add_event(&LifecycleEvent::new(link, Stage::Grabbed))
+                .unwrap();
+            let torrent = TorrentRecord {
+                link: link.to_string(),
+                torrent_id: None,
+                hash: None,
+                name: None,
+                added_at: 1,
+            };
+            store.add_torrent(&torrent).unwrap();
+        }
+        for link in [Some("http://live/old"), None] {
+            let import = ImportRecord {
+                torrent: "t".to_string(),
+                link: link.map(String::from),
+                source: PathBuf::from("/done/a.mkv"),
+                destination: PathBuf::from("/library/a.mkv"),
+                imported_at: 1,
+            };
+            store.add_import(&import).unwrap();
+        }
+
+        assert_eq!(store.prune(&retention(Some(7), None)).unwrap(), 1);
+        assert!(store.events("http://live/old").unwrap().is_empty());
+        assert_eq!(store.events("http://live/current").unwrap().len(), 1);
+        assert_eq!(count(&store, "torrents"), 1);
+        assert_eq!(count(&store, "imports"), 1);
+    }
+
+    #[test]
+    fn forgotten_items_take_their_events_torrents_and_imports_along() {
+        let dir = ScratchDir::new("sqlite-forget-dependents");
+        let store = open(&dir);
+        for link in ["http://a/1?token=x", "http://a/2"] {
+            seen(&store, "http://feed", link, 0);
+            let added = LifecycleEvent {
+                hash: Some("ab".repeat(20)),
+                ..LifecycleEvent::new(link, Stage::Added)
+            };
+            store.add_event(&added).unwrap();
+            let torrent = TorrentRecord {
+                link: link.to_string(),
+                torrent_id: None,
+                hash: added.hash.clone(),
+                name: None,
+                added_at: 1,
+            };
+            store.add_torrent(&torrent).unwrap();
+            let import = ImportRecord {
+                torrent: "t".to_string(),
+                link: Some(link.to_string()),
+                source: PathBuf::from("/done/a.mkv"),
+                destination: PathBuf::from("/library/a.mkv"),
+                imported_at: 1,
+            };
+            store.add_import(&import).unwrap();
+        }
+
+        let forget = Forget::Link("http://a/1?token=y".to_string());
+        assert_eq!(store.forget(&forget).unwrap(), 1);
+        assert!(store.events("http://a/1?token=x").unwrap().is_empty());
+        let tracked: Vec<_> = store
+            .tracked()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.link)
+            .collect();
+        assert_eq!(tracked, ["http://a/2"]);
+        assert_eq!(count(&store, "torrents"), 1);
+        assert_eq!(count(&store, "imports"), 1);
+    }
+
+    #[test]
+    fn a_stale_feed_does_not_hold_back_pruning_by_age() {
+        let dir = ScratchDir::new("sqlite-prune-days");
+        let store = open(&dir);
+        store.polled("http://stale", now() - 100 * DAY).unwrap();
+        store.polled("http://live", now() - 60).unwrap();
+        seen(&store, "http://stale", "http://stale/old", 101 * DAY);
+        seen(&store, "http://live", "http://live/old", 10 * DAY);
+        seen(&store, "http://live", "http://live/current", 30);
+        seen(&store, "http://gone", "http://gone/old", 10 * DAY);
+
+        assert_eq!(store.prune(&retention(Some(7), None)).unwrap(), 3);
+        assert_eq!(links(&store), ["http://live/current"]);
+    }
+
+    #[test]
+    fn items_in_the_current_poll_outlive_the_age_limit() {
+        let dir = ScratchDir::new("sqlite-prune-window");
+        let store = open(&dir);
+        store.polled("http://live", now() - 60).unwrap();
+        seen(&store, "http://live", "http://live/current", 30);
+        assert_eq!(store.prune(&retention(Some(0), None)).unwrap(), 0);
+    }
+
+    #[test]
+    fn per_feed_limits_keep_the_newest_of_each_feed() {
+        let dir = ScratchDir::new("sqlite-prune-per-feed");
+        let store = open(&dir);
+        store.polled("http://a", now() - 60).unwrap();
+        store.polled("http://b", now() - 60).unwrap();
+        for (n, ago) in [(1, 3 * DAY), (2, 2 * DAY), (3, DAY)] {
+            seen(&store, "http://a", &format!("http://a/{}", n), ago);
+            seen(&store, "http://b", &format!("http://b/{}", n), ago);
+        }
+        seen(&store, "http://a", "http://a/current", 30);
+
+        assert_eq!(store.prune(&retention(None, Some(2))).unwrap(), 3);
+        assert_eq!(
+            links(&store),
+            ["http://a/3", "http://a/current", "http://b/2", "http://b/3"]
+        );
+    }
+}
@@ -0,0 +1,571 @@
+use super::keyset::{JsonStore, MemoryStore};
+use super::record::{ImportRecord, ItemRecord, LifecycleEvent, Status, TorrentRecord};
+use super::sqlite::SqliteStore;
+use crate::config::types::{HistoryBackend, HistoryConfig, RetentionConfig};
+use anyhow::{Ok as AnyOk, Result as AnyResult};
+
+/// Which history entries a `forget` removes.
+#[derive(Debug)]
+pub enum Forget {
+    Link(String),
+    Show(String),
+    /// Grab times in unix seconds, inclusive.
+    Range {
+        from: u64,
+        to: u64,
+    },
+}
+
+/// Where the history of RSS items, torrents and imports is kept.
+pub trait Store {
+    /// For each item, true if it was seen before. New items are remembered
+    /// as pending, so a later item in the same batch can be deduped against them.
+    fn check_all(&self, records: &[ItemRecord]) -> AnyResult<Vec<bool>>;
+    fn confirm_all(&self, confirmed: &[(ItemRecord, Status)]) -> AnyResult<()>;
+    /// Called before a poll checks its items. Only stores with retention care.
+    fn polled(&self, _feed: &str, _started: u64) -> AnyResult<()> {
+        AnyOk(())
+    }
+    /// Returns how many entries were removed.
+    fn prune(&self, _retention: &RetentionConfig) -> AnyResult<usize> {
+        AnyOk(0)
+    }
+    fn items(&self) -> AnyResult<Vec<ItemRecord>>;
+    /// Items whose title, link or show contains `text`, ignoring case.
+    fn search(&self, text: &str) -> AnyResult<Vec<ItemRecord>> {
+        let text = text.to_lowercase();
+        AnyOk(
+            self.items()?
+                .into_iter()
+                .filter(|item| {
+                    [Some(&item.link), item.title.as_ref(), item.show.as_ref()]
+                        .into_iter()
+                        .flatten()
+                        .any(|field| field.to_lowercase().contains(&text))
+                })
+                .collect(),
+        )
+    }
+    /// Adds items as they are, returning how many were new.
+    fn import_items(&self, records: &[ItemRecord]) -> AnyResult<usize>;
+    /// Returns how many entries were removed.
+    fn forget(&self, forget: &Forget) -> AnyResult<usize>;
+    fn add_torrent(&self, torrent: &TorrentRecord) -> AnyResult<()>;
+    /// The newest torrent Transmission gave this name.
+    fn find_torrent(&self, name: &str) -> AnyResult<Option<TorrentRecord>>;
+    fn add_import(&self, import: &ImportRecord) -> AnyResult<()>;
+    fn add_event(&self, event: &LifecycleEvent) -> AnyResult<()>;
+    /// Everything that happened to the item with this link, oldest first.
+    fn events(&self, link: &str) -> AnyResult<Vec<LifecycleEvent>>;
+    /// The latest event of every item Transmission should still have, with
+    /// the torrent's id and hash from whichever event last had them.
+    fn tracked(&self) -> AnyResult<Vec<LifecycleEvent>>;
+}
+
+/// Whichever history backend the config asks for, for the daemon and the
+/// commands that change the history. The json file can only be open in one
+/// such process at a time.
+pub fn open(config: &HistoryConfig) -> AnyResult<Box<dyn Store + Send>> {
+    match config.backend {
+        HistoryBackend::Memory | HistoryBackend::Sqlite => open_to_read(config),
+        HistoryBackend::Json => AnyOk(Box::new(JsonStore::open(
+            config.json_path(),
+            config.dedupe,
+        )?)),
+    }
+}
+
+/// Like `open`, for commands that only read the history while the daemon runs.
+pub fn open_to_read(config: &HistoryConfig) -> AnyResult<Box<dyn Store + Send>> {
+    match config.backend {
+        HistoryBackend::Memory => AnyOk(Box::new(MemoryStore::in_memory(config.dedupe))),
+        HistoryBackend::Json => AnyOk(Box::new(JsonStore::open_to_read(
+            config.json_path(),
+            config.dedupe,
+        )?)),
+        HistoryBackend::Sqlite => AnyOk(Box::new(SqliteStore::open(
+            config.sqlite_path(),
+            config.json_path(),
+            config.dedupe,
+        )?)),
+    }
+}
+
+/// Behaviour every backend has to share, run against each of them.
+#[cfg(test)]
+mod conformance {
+    use super::*;
+    use crate::config::types::{DedupeConfig, DedupeKey};
+    use crate::datastore::record::Stage;
+    use crate::journal::client::now;
+    use crate::testing::ScratchDir;
+    use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+    use std::path::PathBuf;
+
+    fn config(dir: &ScratchDir, backend: HistoryBackend, dedupe: DedupeConfig) -> HistoryConfig {
+        HistoryConfig {
+            backend,
+            json_path: dir.join("save").to_string_lossy().to_string(),
+            sqlite_path: dir.join("history.db").to_string_lossy().to_string(),
+            dedupe,
+            ..Default::default()
+        }
+    }
+
+    /// Runs `test`, naming `backend` in the message if it fails.
+    fn against(backend: HistoryBackend, test: impl FnOnce()) {
+        if let Err(panic) = catch_unwind(AssertUnwindSafe(test)) {
+            let message = panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()));
+            match message {
+                Some(message) => panic!("{:?} backend: {}", backend, message),
+                None => resume_unwind(panic),
+            }
+        }
+    }
+
+    /// Runs `test` on a fresh store of every backend, each with its files
+    /// in a scratch dir of its own.
+    fn each_backend(dedupe: DedupeConfig, test: impl Fn(&dyn Store)) {
+        for backend in [
+            HistoryBackend::Memory,
+            HistoryBackend::Json,
+            HistoryBackend::Sqlite,
+        ] {
+            let dir = ScratchDir::new("history-conformance");
+            let config = config(&dir, backend, dedupe);
+            against(backend, || test(open(&config).unwrap().as_ref()));
+        }
+    }
+
+    /// Runs `before` on a fresh store of every backend that keeps its
+    /// history on disk, then `after` on the same files opened again.
+    fn each_restart(dedupe: DedupeConfig, before: impl Fn(&dyn Store), after: impl Fn(&dyn Store)) {
+        for backend in [HistoryBackend::Json, HistoryBackend::Sqlite] {
+            let dir = ScratchDir::new("history-restart");
+            let config = config(&dir, backend, dedupe);
+            against(backend, || {
+                before(open(&config).unwrap().as_ref());
+                after(open(&config).unwrap().as_ref());
+            });
+        }
+    }
+
+    fn by_link() -> DedupeConfig {
+        DedupeConfig::default()
+    }
+
+    fn by_episode(upgrades: bool) -> DedupeConfig {
+        DedupeConfig {
+            key: DedupeKey::Episode,
+            upgrades,
+        }
+    }
+
+    fn item(link: &str, title: &str) -> ItemRecord {
+        ItemRecord {
+            feed: "http://feed".to_string(),
+            link: link.to_string(),
+            title: Some(title.to_string()),
+            show: Some("the.show".to_string()),
+            season: Some(1),
+            episode: Some(2),
+            ..Default::default()
+        }
+    }
+
+    fn check(store: &dyn Store, record: &ItemRecord) -> bool {
+        store.check_all(std::slice::from_ref(record)).unwrap()[0]
+    }
+
+    fn answer(store: &dyn Store, record: &ItemRecord, status: Status) {
+        store.confirm_all(&[(record.clone(), status)]).unwrap()
+    }
+
+    #[test]
+    fn answered_items_are_seen() {
+        each_backend(by_link(), |store| {
+            let submitted = item("http://a/1", "The.Show.S01E02.720p");
+            let rejected = item("http://a/2", "The.Show.S01E02.1080p");
+            assert!(!check(store, &submitted));
+            assert!(!check(store, &rejected));
+            answer(store, &submitted, Status::Submitted);
+            answer(store, &rejected, Status::Rejected);
+            assert!(check(store, &submitted));
+            assert!(check(store, &rejected));
+        })
+    }
+
+    #[test]
+    fn failed_and_pending_items_are_tried_again() {
+        each_backend(by_link(), |store| {
+            let record = item("http://a/1", "The.Show.S01E02.720p");
+            assert!(!check(store, &record));
+            assert!(!check(store, &record));
+            answer(store, &record, Status::Failed);
+            assert!(!check(store, &record));
+        })
+    }
+
+    #[test]
+    fn query_strings_do_not_make_new_items() {
+        each_backend(by_link(), |store| {
+            let record = item("http://a/1?token=old", "The.Show.S01E02.720p");
+            check(store, &record);
+            answer(store, &record, Status::Submitted);
+            assert!(check(
+                store,
+                &item("http://a/1?token=new", "The.Show.S01E02.720p")
+            ));
+        })
+    }
+
+    #[test]
+    fn one_release_per_episode_in_a_batch() {
+        each_backend(by_episode(false), |store| {
+            let batch = [
+                item("http://a/1", "The.Show.S01E02.720p"),
+                item("http://a/2", "The.Show.S01E02.1080p"),
+            ];
+            assert_eq!(store.check_all(&batch).unwrap(), vec![false, true]);
+        })
+    }
+
+    #[test]
+    fn only_better_releases_are_upgrades() {
+        each_backend(by_episode(true), |store| {
+            let first = item("http://a/1", "The.Show.S01E02.720p");
+            check(store, &first);
+            answer(store, &first, Status::Submitted);
+            assert!(!check(store, &item("http://a/2", "The.Show.S01E02.1080p")));
+            assert!(check(store, &item("http://a/3", "The.Show.S01E02.480p")));
+        })
+    }
+
+    #[test]
+    fn shows_under_another_spelling_are_the_same_episode() {
+        each_backend(by_episode(false), |store| {
+            let first = item("http://a/1", "The.Show.S01E02.720p");
+            check(store, &first);
+            answer(store, &first, Status::Submitted);
+            let respelled = ItemRecord {
+                show: Some("The Show -".to_string()),
+                ..item("http://a/2", "The Show - S01E02 720p")
+            };
+            assert!(check(store, &respelled));
+        })
+    }
+
+    #[test]
+    fn forgetting_a_show_forgets_its_episodes() {
+        each_backend(by_episode(false), |store| {
+            let record = item("http://a/1", "The.Show.S01E02.720p");
+            check(store, &record);
+            answer(store, &record, Status::Submitted);
+            assert_eq!(
+                store.forget(&Forget::Show("The Show".to_string())).unwrap(),
+                1
+            );
+            assert!(!check(store, &record));
+        })
+    }
+
+    #[test]
+    fn pruning_keeps_what_the_current_poll_saw() {
+        let retention = RetentionConfig {
+            days: Some(0),
+            per_feed: Some(0),
+            ..Default::default()
+        };
+        each_backend(by_link(), |store| {
+            let record = ItemRecord {
+                grabbed_at: now(),
+                ..item("http://a/1", "The.Show.S01E02.720p")
+            };
+            store.polled(&record.feed, now() - 60).unwrap();
+            check(store, &record);
+            answer(store, &record, Status::Submitted);
+            assert_eq!(store.prune(&retention).unwrap(), 0);
+            assert!(check(store, &record));
+
+            store.polled(&record.feed, now() + 1).unwrap();
+            assert_eq!(store.prune(&retention).unwrap(), 1);
+            assert!(!check(store, &record));
+        })
+    }
+
+    #[test]
+    fn imports_add_only_new_items() {
+        each_backend(by_link(), |store| {
+            let record = ItemRecord {
+                status: Status::Submitted,
+                ..item("http://a/1", "The.Show.S01E02.720p")
+            };
+            assert_eq!(
+                store.import_items(std::slice::from_ref(&record)).unwrap(),
+                1
+            );
+            assert_eq!(
+                store.import_items(std::slice::from_ref(&record)).unwrap(),
+                0
+            );
+            assert!(check(store, &record));
+            assert!(store
+                .items()
+                .unwrap()
+                .iter()
+                .any(|item| item.link == "http://a/1"));
+            assert_eq!(store.search("a/1").unwrap().len(), 1);
+        })
+    }
+
+    #[test]
+    fn forgotten_items_are_new_again() {
+        each_backend(by_link(), |store| {
+            let record = item("http://a/1", "The.Show.S01E02.720p");
+            check(store, &record);
+            answer(store, &record, Status::Submitted);
+            assert!(store.forget(&Forget::Link(record.link.clone())).unwrap() > 0);
+            assert!(!check(store, &record));
+        })
+    }
+
+    #[test]
+    fn torrents_are_found_by_newest_name() {
+        each_backend(by_link(), |store| {
+            for (link, added_at) in [("http://a/1", 1), ("http://a/2", 2)] {
+                let torrent = TorrentRecord {
+                    link: link.to_string(),
+                    torrent_id: Some(added_at as i64),
+                    hash: None,
+                    name: Some("The.Show.S01E02".to_string()),
+                    added_at,
+                };
+                store.add_torrent(&torrent).unwrap();
+            }
+            let found = store.find_torrent("The.Show.S01E02").unwrap().unwrap();
+            assert_eq!(found.link, "http://a/2");
+            assert!(store.find_torrent("Other").unwrap().is_none());
+        })
+    }
+
+    #[test]
+    fn everything_survives_a_restart() {
+        let link = "http://a/1";
+        each_restart(
+            by_link(),
+            |store| {
+                let record = item(link, "The.Show.S01E02.720p");
+                check(store, &record);
+                answer(store, &record, Status::Submitted);
+                let torrent = TorrentRecord {
+                    link: link.to_string(),
+                    torrent_id: Some(7),
+                    hash: Some("ab".repeat(20)),
+                    name: Some("The.Show.S01E02".to_string()),
+                    added_at: 1,
+                };
+                store.add_torrent(&torrent).unwrap();
+                store
+                    .add_import(&ImportRecord {
+                        torrent: "The.Show.S01E02".to_string(),
+                        link: Some(link.to_string()),
+                        source: PathBuf::from("/done/e02.mkv"),
+                        destination: PathBuf::from("/tv/The Show/e02.mkv"),
+                        imported_at: 2,
+                    })
+                    .unwrap();
+                store
+                    .add_event(&LifecycleEvent::new(link, Stage::Grabbed))
+                    .unwrap();
+                store
+                    .add_event(&LifecycleEvent {
+                        torrent_id: torrent.torrent_id,
+                        hash: torrent.hash.clone(),
+                        ..LifecycleEvent::new(link, Stage::Added)
+                    })
+                    .unwrap();
+                store
+                    .add_event(&LifecycleEvent {
+                        path: Some(PathBuf::from("/tv/The Show/e02.mkv")),
+                        ..LifecycleEvent::new(link, Stage::Imported)
+                    })
+                    .unwrap();
+            },
+            |store| {
+                assert!(check(store, &item(link, "The.Show.S01E02.720p")));
+                let found = store.find_torrent("The.Show.S01E02").unwrap();
+                assert_eq!(found.map(|torrent| torrent.torrent_id), Some(Some(7)));
+                let events = store.events(link).unwrap();
+                let stages: Vec<_> = events.iter().map(|event| event.stage).collect();
+                assert_eq!(stages, [Stage::Grabbed, Stage::Added, Stage::Imported]);
+                assert_eq!(
+                    events[2].path.as_deref(),
+                    Some(std::path::Path::new("/tv/The Show/e02.mkv"))
+                );
+                let tracked = store.tracked().unwrap();
+                assert_eq!(tracked.len(), 1);
+                assert_eq!(tracked[0].torrent_id, Some(7));
+            },
+        )
+    }
+
+    #[test]
+    fn tracking_follows_the_latest_event() {
+        each_backend(by_link(), |store| {
+            let link = "http://a/1";
+            store
+                .add_event(&LifecycleEvent::new(link, Stage::Grabbed))
+                .unwrap();
+            assert!(store.tracked().unwrap().is_empty());
+            let added = LifecycleEvent {
+                torrent_id: Some(7),
+                hash: Some("ab".repeat(20)),
+                ..LifecycleEvent::new(link, Stage::Added)
+            };
+            store.add_event(&added).unwrap();
+            store
+                .add_event(&LifecycleEvent::new(link, Stage::Downloading))
+                .unwrap();
+            let tracked = store.tracked().unwrap();
+            assert_eq!(tracked.len(), 1);
+            assert_eq!(tracked[0].stage, Stage::Downloading);
+            assert_eq!(tracked[0].torrent_id, Some(7));
+            assert_eq!(tracked[0].hash, added.hash);
+            store
+                .add_event(&LifecycleEvent::new(link, Stage::Removed))
+                .unwrap();
+            assert!(store.tracked().unwrap().is_empty());
+            let stages: Vec<_> = store
+                .events(link)
+                .unwrap()
+                .iter()
+                .map(|event| event.stage)
+                .collect();
+            assert_eq!(
+                stages,
+                vec![
+                    Stage::Grabbed,
+                    Stage::Added,
+                    Stage::Downloading,
+                    Stage::Removed
+                ]
+            );
+        })
+    }
+}
+
+/// Files and databases from older and newer builds.
+#[cfg(test)]
+mod versions {
+    use super::*;
+    use crate::config::types::DedupeConfig;
+    use crate::datastore::record::Stage;
+    use crate::testing::ScratchDir;
+    use rusqlite::Connection;
+    use serde_json::Value;
+    use std::fs;
+
+    fn config(dir: &ScratchDir, backend: HistoryBackend) -> HistoryConfig {
+        HistoryConfig {
+            backend,
+            json_path: dir.join("save").to_string_lossy().to_string(),
+            sqlite_path: dir.join("history.db").to_string_lossy().to_string(),
+            dedupe: DedupeConfig::default(),
+            ..Default::default()
+        }
+    }
+
+    fn link(link: &str) -> ItemRecord {
+        ItemRecord {
+            feed: "http://feed".to_string(),
+            link: link.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn version_1_save_files_drop_the_empty_sentinel() {
+        let dir = ScratchDir::new("versions-json-v1");
+        fs::write(dir.join("save"), r#"["", "http://a/1"]"#).unwrap();
+        let store = open(&config(&dir, HistoryBackend::Json)).unwrap();
+        let links: Vec<_> = store.items().unwrap().into_iter().map(|i| i.link).collect();
+        assert_eq!(links, ["http://a/1"]);
+
+        store
+            .confirm_all(&[(link("http://a/2"), Status::Submitted)])
+            .unwrap();
+        let saved: Value = serde_json::from_slice(&fs::read(dir.join("save")).unwrap()).unwrap();
+        assert_eq!(saved["version"], 4);
+        assert_eq!(saved["keys"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn save_files_from_a_newer_build_are_refused() {
+        let dir = ScratchDir::new("versions-json-newer");
+        let newer = r#"{ "version": 99, "keys": ["http://a/1"] }"#;
+        fs::write(dir.join("save"), newer).unwrap();
+        fs::write(dir.join("save.1"), r#"{ "version": 3, "keys": [] }"#).unwrap();
+        let error = open(&config(&dir, HistoryBackend::Json)).err().unwrap();
+        assert!(format!("{:#}", error).contains("newer"), "{:#}", error);
+        assert_eq!(fs::read_to_string(dir.join("save")).unwrap(), newer);
+    }
+
+    #[test]
+    fn unversioned_databases_go_through_every_migration() {
+        let dir = ScratchDir::new("versions-sqlite-legacy");
+        let legacy = Connection::open(dir.join("history.db")).unwrap();
+        legacy
+            .execute_batch(
+                "CREATE TABLE items (
+                    id INTEGER PRIMARY KEY,
+                    feed TEXT NOT NULL,
+                    guid TEXT,
+                    link TEXT NOT NULL,
+                    title TEXT,
+                    show TEXT,
+                    season INTEGER,
+                    episode INTEGER,
+                    infohash TEXT,
+                    pub_date TEXT,
+                    grabbed_at INTEGER NOT NULL,
+                    status TEXT NOT NULL
+                );
+                INSERT INTO items (feed, link, show, season, episode, grabbed_at, status)
+                    VALUES ('http://feed', 'http://a/1?token=old', 'The.Show', 1, 2, 5, 'submitted');",
+            )
+            .unwrap();
+        drop(legacy);
+
+        let store = open(&config(&dir, HistoryBackend::Sqlite)).unwrap();
+        assert!(store.check_all(&[link("http://a/1?token=new")]).unwrap()[0]);
+        assert_eq!(store.items().unwrap()[0].show.as_deref(), Some("the show"));
+        let stages: Vec<_> = store
+            .events("http://a/1?token=old")
+            .unwrap()
+            .iter()
+            .map(|event| event.stage)
+            .collect();
+        assert_eq!(stages, [Stage::Grabbed]);
+        drop(store);
+
+        let version: i64 = Connection::open(dir.join("history.db"))
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 5);
+    }
+
+    #[test]
+    fn databases_from_a_newer_build_are_refused() {
+        let dir = ScratchDir::new("versions-sqlite-newer");
+        Connection::open(dir.join("history.db"))
+            .unwrap()
+            .pragma_update(None, "user_version", 99)
+            .unwrap();
+        let error = open(&config(&dir, HistoryBackend::Sqlite)).err().unwrap();
+        assert!(error.to_string().contains("newer"), "{}", error);
+    }
+}
@@ -0,0 +1,34 @@
+use super::record::ItemRecord;
+use anyhow::{Ok as AnyOk, Result as AnyResult};
+use std::fs::{self, File};
+use std::path::Path;
+
+/// History files ending in `.csv` are CSV, anything else is a JSON array.
+fn is_csv(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"))
+}
+
+pub fn export(file: &Path, items: &[ItemRecord]) -> AnyResult<()> {
+    if is_csv(file) {
+        let mut writer = csv::Writer::from_path(file)?;
+        for item in items {
+            writer.serialize(item)?;
+        }
+        writer.flush()?;
+    } else {
+        serde_json::to_writer_pretty(File::create(file)?, items)?;
+    }
+    AnyOk(())
+}
+
+pub fn import(file: &Path) -> AnyResult<Vec<ItemRecord>> {
+    if is_csv(file) {
+        let items = csv::Reader::from_path(file)?
+            .deserialize()
+            .collect::<Result<Vec<ItemRecord>, _>>()?;
+        AnyOk(items)
+    } else {
+        AnyOk(serde_json::from_slice(&fs::read(file)?)?)
+    }
+}
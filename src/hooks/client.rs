@@ -0,0 +1,290 @@
+use crate::config::path_functions::ReleaseInfo;
+use crate::config::types::{Hook, HooksConfig};
+use anyhow::{bail, Context, Ok as AnyOk, Result as AnyResult};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time::{sleep, timeout};
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum HookEvent {
+    #[serde(rename = "on_import")]
+    Import,
+    #[serde(rename = "on_failure")]
+    Failure,
+    #[serde(rename = "on_grab")]
+    Grab,
+}
+
+impl HookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Import => "on_import",
+            HookEvent::Failure => "on_failure",
+            HookEvent::Grab => "on_grab",
+        }
+    }
+}
+
+/// Everything a hook gets told, as JSON on stdin and as `MOVER_*` variables.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct HookPayload {
+    #[serde(with = "crate::config::raw_path::option")]
+    pub source: Option<PathBuf>,
+    #[serde(with = "crate::config::raw_path::option")]
+    pub destination: Option<PathBuf>,
+    pub release: Option<ReleaseInfo>,
+    pub link: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl HookPayload {
+    fn env(&self, event: HookEvent) -> Vec<(&'static str, String)> {
+        let mut env = vec![("MOVER_EVENT", event.name().to_string())];
+        let path = |p: &Option<PathBuf>| p.as_ref().map(|p| p.to_string_lossy().to_string());
+        if let Some(source) = path(&self.source) {
+            env.push(("MOVER_SOURCE", source));
+        }
+        if let Some(destination) = path(&self.destination) {
+            env.push(("MOVER_DESTINATION", destination));
+        }
+        if let Some(release) = &self.release {
+            let kind = if release.movie { "movie" } else { "episode" };
+            env.push(("MOVER_KIND", kind.to_string()));
+            env.push(("MOVER_NAME", release.name.clone()));
+            if let Some(show) = &release.show {
+                env.push(("MOVER_SHOW", show.clone()));
+            }
+            if let Some(season) = release.season {
+                env.push(("MOVER_SEASON", season.to_string()));
+            }
+            if let Some(episode) = release.episode {
+                env.push(("MOVER_EPISODE", episode.to_string()));
+            }
+        }
+        if let Some(link) = &self.link {
+            env.push(("MOVER_LINK", link.clone()));
+        }
+        if let Some(reason) = &self.reason {
+            env.push(("MOVER_REASON", reason.clone()));
+        }
+        env
+    }
+}
+
+#[derive(Serialize)]
+struct HookInput<'a> {
+    event: HookEvent,
+    #[serde(flatten)]
+    payload: &'a HookPayload,
+}
+
+pub struct Hooks {
+    config: HooksConfig,
+}
+
+impl Hooks {
+    pub fn new(config: &HooksConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    /// Runs every hook registered for `event`, one after the other. Failures
+    /// are logged and never stop the caller.
+    pub async fn fire(&self, event: HookEvent, payload: &HookPayload) {
+        let hooks = match event {
+            HookEvent::Import => &self.config.on_import,
+            HookEvent::Failure => &self.config.on_failure,
+            HookEvent::Grab => &self.config.on_grab,
+        };
+        for hook in hooks {
+            if let Err(e) = run_with_retries(hook, event, payload).await {
+                println!("{} hook {:?} failed: {:?}", event.name(), hook.command, e)
+            }
+        }
+    }
+}
+
+async fn run_with_retries(hook: &Hook, event: HookEvent, payload: &HookPayload) -> AnyResult<()> {
+    let mut attempt = 0;
+    loop {
+        match run_once(hook, event, payload).await {
+            Ok(output) => {
+                println!(
+                    "{} hook {:?} done\nstdout: {}\nstderr: {}",
+                    event.name(),
+                    hook.command,
+                    String::from_utf8_lossy(&output.stdout).trim(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                return AnyOk(());
+            }
+            Err(e) if attempt < hook.retries => {
+                attempt += 1;
+                println!(
+                    "{} hook {:?} failed ({:?}), retry {} of {}",
+                    event.name(),
+                    hook.command,
+                    e,
+                    attempt,
+                    hook.retries
+                );
+                sleep(Duration::from_secs(2u64.pow(attempt.min(6)))).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn run_once(hook: &Hook, event: HookEvent, payload: &HookPayload) -> AnyResult<Output> {
+    let (program, args) = hook.command.split_first().context("hook has no command")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .envs(payload.env(event))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let input = serde_json::to_vec(&HookInput { event, payload })?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook that ignores stdin may exit before reading it.
+        let _ = stdin.write_all(&input).await;
+    }
+
+    let output = match timeout(hook.timeout(), child.wait_with_output()).await {
+        Ok(output) => output?,
+        Err(_) => bail!("timed out after {:?}", hook.timeout()),
+    };
+    if !output.status.success() {
+        bail!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    }
+    AnyOk(output)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+    use serde_json::{json, Value};
+    use std::fs;
+
+    fn hook(script: String, fields: Value) -> Hook {
+        let mut raw = json!({ "command": ["sh", "-c", script] });
+        raw.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(raw).unwrap()
+    }
+
+    fn payload() -> HookPayload {
+        HookPayload {
+            source: Some(PathBuf::from("/srv/done/The.Show.S01E02.mkv")),
+            destination: Some(PathBuf::from(
+                "/srv/library/tv/the.show/01/The.Show.S01E02.mkv",
+            )),
+            release: Some(ReleaseInfo {
+                movie: false,
+                show: Some("the.show".to_string()),
+                season: Some(1),
+                episode: Some(2),
+                name: "The.Show.S01E02.mkv".to_string(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_get_the_payload_on_stdin_and_in_the_environment() {
+        let dir = ScratchDir::new("hooks");
+        let script = format!(
+            "cat > {0}/stdin; env | grep ^MOVER_ | sort > {0}/env",
+            dir.path().display()
+        );
+        let hooks = Hooks::new(&HooksConfig {
+            on_import: vec![hook(script, json!({}))],
+            ..Default::default()
+        });
+        hooks.fire(HookEvent::Import, &payload()).await;
+
+        let stdin: Value = serde_json::from_slice(&fs::read(dir.join("stdin")).unwrap()).unwrap();
+        assert_eq!(stdin["event"], "on_import");
+        assert_eq!(stdin["source"], "/srv/done/The.Show.S01E02.mkv");
+        assert_eq!(stdin["release"]["episode"], 2);
+        let env = fs::read_to_string(dir.join("env")).unwrap();
+        assert_eq!(
+            env.lines().collect::<Vec<_>>(),
+            [
+                "MOVER_DESTINATION=/srv/library/tv/the.show/01/The.Show.S01E02.mkv",
+                "MOVER_EPISODE=2",
+                "MOVER_EVENT=on_import",
+                "MOVER_KIND=episode",
+                "MOVER_NAME=The.Show.S01E02.mkv",
+                "MOVER_SEASON=1",
+                "MOVER_SHOW=the.show",
+                "MOVER_SOURCE=/srv/done/The.Show.S01E02.mkv",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_hooks_for_the_event_run() {
+        let dir = ScratchDir::new("hooks-event");
+        let touch = |name: &str| {
+            hook(
+                format!("touch {}/{}", dir.path().display(), name),
+                json!({}),
+            )
+        };
+        let hooks = Hooks::new(&HooksConfig {
+            on_import: vec![touch("import")],
+            on_failure: vec![touch("failure")],
+            on_grab: vec![touch("grab")],
+        });
+        hooks
+            .fire(HookEvent::Failure, &HookPayload::default())
+            .await;
+        assert!(dir.join("failure").exists());
+        assert!(!dir.join("import").exists());
+        assert!(!dir.join("grab").exists());
+    }
+
+    #[tokio::test]
+    async fn failing_hooks_are_retried() {
+        let dir = ScratchDir::new("hooks-retry");
+        // Fails the first time, when the marker does not exist yet.
+        let script = format!(
+            "test -e {0}/tried && touch {0}/done || {{ touch {0}/tried; exit 1; }}",
+            dir.path().display()
+        );
+        let flaky = hook(script, json!({ "retries": 1 }));
+        run_with_retries(&flaky, HookEvent::Import, &HookPayload::default())
+            .await
+            .unwrap();
+        assert!(dir.join("done").exists());
+    }
+
+    #[tokio::test]
+    async fn slow_hooks_time_out_and_failures_carry_stderr() {
+        let slow = hook("sleep 5".to_string(), json!({ "timeoutSecs": 0 }));
+        let error = run_once(&slow, HookEvent::Import, &HookPayload::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("timed out"), "{}", error);
+
+        let failing = hook("echo broken >&2; exit 3".to_string(), json!({}));
+        let error = run_once(&failing, HookEvent::Import, &HookPayload::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().ends_with("broken"), "{}", error);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::config::types::ImportMode;
+use anyhow::{bail, Ok as AnyOk, Result as AnyResult};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Move,
+    Copy,
+    Hardlink,
+    Undo,
+}
+
+impl From<ImportMode> for Mode {
+    fn from(mode: ImportMode) -> Self {
+        match mode {
+            ImportMode::Move => Mode::Move,
+            ImportMode::Copy => Mode::Copy,
+            ImportMode::Hardlink => Mode::Hardlink,
+        }
+    }
+}
+
+/// One line of the journal, written for every file the mover touches.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub id: u64,
+    pub timestamp: u64,
+    pub torrent: String,
+    #[serde(with = "crate::config::raw_path")]
+    pub source: PathBuf,
+    #[serde(with = "crate::config::raw_path")]
+    pub destination: PathBuf,
+    pub mode: Mode,
+    pub hash: Option<String>,
+    /// Set on `Undo` entries to the id of the entry that was reverted.
+    #[serde(default)]
+    pub reverts: Option<u64>,
+}
+
+/// Which entries an `undo` should revert.
+#[derive(Debug)]
+pub enum Selection {
+    Id(u64),
+    Range { from: u64, to: u64 },
+    Torrent(String),
+}
+
+/// Append only, line delimited JSON log of every move made by `copy_file`.
+#[derive(Debug)]
+pub struct Journal {
+    file: PathBuf,
+    next_id: Mutex<u64>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn hash_file(path: &Path) -> AnyResult<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    AnyOk(
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+impl Journal {
+    pub fn open(file: PathBuf) -> AnyResult<Journal> {
+        let next_id = read_entries(&file)?
+            .iter()
+            .map(|e| e.id + 1)
+            .max()
+            .unwrap_or(1);
+        AnyOk(Self {
+            file,
+            next_id: Mutex::new(next_id),
+        })
+    }
+
+    pub fn entries(&self) -> AnyResult<Vec<Entry>> {
+        read_entries(&self.file)
+    }
+
+    /// Writes a new entry and syncs it to disk before returning.
+    pub fn record(
+        &self,
+        torrent: &str,
+        source: &Path,
+        destination: &Path,
+        mode: Mode,
+        reverts: Option<u64>,
+    ) -> AnyResult<Entry> {
+        let hash = if destination.is_file() {
+            Some(hash_file(destination)?)
+        } else {
+            None
+        };
+        let mut next_id = self.next_id.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = Entry {
+            id: *next_id,
+            timestamp: now(),
+            torrent: torrent.to_string(),
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            mode,
+            hash,
+            reverts,
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?;
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.sync_all()?;
+        *next_id += 1;
+        AnyOk(entry)
+    }
+
+    /// Moves every selected file back to where it was imported from, newest first.
+    pub fn undo(&self, selection: &Selection) -> AnyResult<Vec<Entry>> {
+        let entries = self.entries()?;
+        let reverted: HashSet<u64> = entries.iter().filter_map(|e| e.reverts).collect();
+        let mut selected: Vec<&Entry> = entries
+            .iter()
+            .filter(|e| e.mode != Mode::Undo && !reverted.contains(&e.id))
+            .filter(|e| match selection {
+                Selection::Id(id) => e.id == *id,
+                Selection::Range { from, to } => (*from..=*to).contains(&e.timestamp),
+                Selection::Torrent(name) => &e.torrent == name,
+            })
+            .collect();
+        selected.reverse();
+
+        let mut done = vec![];
+        for entry in selected {
+            match self.revert(entry) {
+                Ok(undo) => done.push(undo),
+                Err(err) => println!("could not undo entry {}: {:?}", entry.id, err),
+            }
+        }
+        AnyOk(done)
+    }
+
+    fn revert(&self, entry: &Entry) -> AnyResult<Entry> {
+        if !entry.destination.is_file() {
+            bail!("{:?} no longer exists", entry.destination)
+        }
+        if let Some(expected) = &entry.hash {
+            if &hash_file(&entry.destination)? != expected {
+                bail!("{:?} has changed since it was imported", entry.destination)
+            }
+        }
+        // Copies and hardlinks left the source behind, so the library copy just goes.
+        let source_kept = matches!(entry.mode, Mode::Copy | Mode::Hardlink);
+        if source_kept && entry.source.is_file() {
+            fs::remove_file(&entry.destination)?;
+        } else if entry.source.exists() {
+            bail!("{:?} is already occupied", entry.source)
+        } else {
+            if let Some(parent) = entry.source.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if fs::rename(&entry.destination, &entry.source).is_err() {
+                fs::copy(&entry.destination, &entry.source)?;
+                fs::remove_file(&entry.destination)?;
+            }
+        }
+        self.record(
+            &entry.torrent,
+            &entry.destination,
+            &entry.source,
+            Mode::Undo,
+            Some(entry.id),
+        )
+    }
+}
+
+fn read_entries(path: &Path) -> AnyResult<Vec<Entry>> {
+    if !path.is_file() {
+        return AnyOk(vec![]);
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Entry>(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => println!("skipping bad journal line: {:?}", err),
+        }
+    }
+    AnyOk(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    /// Moves `name` from `downloads` to `library` and journals it as `torrent`.
+    fn import(journal: &Journal, dir: &ScratchDir, torrent: &str, name: &str) -> Entry {
+        let source = dir.join("downloads").join(name);
+        let destination = dir.join("library").join(name);
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::create_dir_all(destination.parent().unwrap()).unwrap();
+        fs::write(&source, name).unwrap();
+        fs::rename(&source, &destination).unwrap();
+        journal
+            .record(torrent, &source, &destination, Mode::Move, None)
+            .unwrap()
+    }
+
+    #[test]
+    fn ids_carry_on_after_reopening() {
+        let dir = ScratchDir::new("journal");
+        let journal = Journal::open(dir.join("journal")).unwrap();
+        assert_eq!(import(&journal, &dir, "a", "a.mkv").id, 1);
+        assert_eq!(import(&journal, &dir, "b", "b.mkv").id, 2);
+        let journal = Journal::open(dir.join("journal")).unwrap();
+        let entry = import(&journal, &dir, "c", "c.mkv");
+        assert_eq!(entry.id, 3);
+        assert!(entry.hash.is_some());
+        assert_eq!(journal.entries().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn undo_by_id_moves_the_file_back_once() {
+        let dir = ScratchDir::new("journal");
+        let journal = Journal::open(dir.join("journal")).unwrap();
+        let entry = import(&journal, &dir, "a", "a.mkv");
+        import(&journal, &dir, "b", "b.mkv");
+
+        let reverted = journal.undo(&Selection::Id(entry.id)).unwrap();
+        assert_eq!(reverted.len(), 1);
+        assert_eq!(reverted[0].reverts, Some(entry.id));
+        assert_eq!(reverted[0].mode, Mode::Undo);
+        assert!(entry.source.is_file());
+        assert!(!entry.destination.exists());
+        assert!(dir.join("library/b.mkv").is_file());
+
+        assert!(journal.undo(&Selection::Id(entry.id)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn undo_by_torrent_only_touches_that_torrent() {
+        let dir = ScratchDir::new("journal");
+        let journal = Journal::open(dir.join("journal")).unwrap();
+        import(&journal, &dir, "pack", "e01.mkv");
+        import(&journal, &dir, "pack", "e02.mkv");
+        import(&journal, &dir, "other", "movie.mkv");
+
+        let reverted = journal
+            .undo(&Selection::Torrent("pack".to_string()))
+            .unwrap();
+        assert_eq!(reverted.len(), 2);
+        assert!(dir.join("downloads/e01.mkv").is_file());
+        assert!(dir.join("downloads/e02.mkv").is_file());
+        assert!(dir.join("library/movie.mkv").is_file());
+    }
+
+    #[test]
+    fn undo_by_range_goes_by_timestamp() {
+        let dir = ScratchDir::new("journal");
+        let journal = Journal::open(dir.join("journal")).unwrap();
+        let entry = import(&journal, &dir, "a", "a.mkv");
+
+        let before = Selection::Range {
+            from: 0,
+            to: entry.timestamp - 1,
+        };
+        assert!(journal.undo(&before).unwrap().is_empty());
+        let around = Selection::Range {
+            from: entry.timestamp,
+            to: now(),
+        };
+        assert_eq!(journal.undo(&around).unwrap().len(), 1);
+        assert!(entry.source.is_file());
+    }
+
+    #[test]
+    fn undo_leaves_changed_files_alone() {
+        let dir = ScratchDir::new("journal");
+        let journal = Journal::open(dir.join("journal")).unwrap();
+        let entry = import(&journal, &dir, "a", "a.mkv");
+        fs::write(&entry.destination, "edited").unwrap();
+
+        assert!(journal.undo(&Selection::Id(entry.id)).unwrap().is_empty());
+        assert!(entry.destination.is_file());
+        assert!(!entry.source.exists());
+    }
+
+    #[test]
+    fn copies_are_undone_by_removing_the_library_file() {
+        let dir = ScratchDir::new("journal");
+        let journal = Journal::open(dir.join("journal")).unwrap();
+        let source = dir.join("a.mkv");
+        let destination = dir.join("library-a.mkv");
+        fs::write(&source, "a").unwrap();
+        fs::copy(&source, &destination).unwrap();
+        let entry = journal
+            .record("a", &source, &destination, Mode::Copy, None)
+            .unwrap();
+
+        assert_eq!(journal.undo(&Selection::Id(entry.id)).unwrap().len(), 1);
+        assert!(source.is_file());
+        assert!(!destination.exists());
+    }
+}
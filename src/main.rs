@@ -1,28 +1,45 @@
-use crate::datastore::client::{check, Client, Restorable, RssSave};
+use crate::cli::commands::Command;
+use crate::datastore::handle::Datastore;
+use crate::datastore::record::{
+    ImportRecord, ItemRecord, LifecycleEvent, Stage, Status, TorrentRecord,
+};
+use crate::datastore::store;
+use crate::hooks::client::{HookEvent, HookPayload, Hooks};
+use crate::journal::client::{now, Journal};
+use crate::mediaserver::client::LibraryRefresher;
+use crate::notifier::client::{Notification, Notifier};
 use crate::rpc::client::RpcClient;
-use crate::rpc::methods::AddType::FileName;
-use crate::rpc::methods::TorrentActions::Add;
+use crate::rpc::tracker::track;
 use crate::rss::client::RssWatcher;
+use crate::watcher::backend::async_watcher;
+use crate::watcher::reconcile::reconcile;
+use crate::watcher::stabilise::Stabiliser;
+use crate::watcher::unit::{UnitState, Units};
 use anyhow::{Ok as AnyOk, Result as AnyResult};
-use config::{path_functions::copy_file, types::MovieConfig};
+use config::path_functions::{copy_file, release_info, Outcome};
+use config::types::MovieConfig;
 use futures::future::join_all;
-use notify::{
-    Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Result as NResult, Watcher,
-};
-use std::cell::RefCell;
 use std::fs;
-use std::path::PathBuf;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::main;
-use tokio::sync::broadcast::channel;
-use tokio::sync::mpsc::{channel as ms_channel, Receiver as ms_Receiver};
-use tokio::sync::oneshot::Sender as OneSender;
-use tokio::task::JoinHandle;
+use tokio::sync::mpsc::{channel, unbounded_channel};
+use tokio::sync::Semaphore;
+use tokio::task::{spawn_blocking, JoinHandle};
 
+mod cli;
 mod config;
 mod datastore;
+mod hooks;
+mod journal;
+mod mediaserver;
+mod nfo;
+mod notifier;
 mod rpc;
 mod rss;
+#[cfg(test)]
+mod testing;
+mod watcher;
 
 /// Async, futures channel based event watching
 #[main]
@@ -31,22 +48,45 @@ async fn main() {
 
     let config = Arc::new(MovieConfig::new(raw_config_file));
 
-    let (tx, mut rx) = channel::<String>(20);
-    let (tx_check, mut rx_check) = ms_channel::<(String, OneSender<bool>)>(1);
+    // A failed command exits non-zero, so scripts can tell.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match Command::parse(&args) {
+        Ok(Some(command)) => {
+            if let Err(e) = command.run(config).await {
+                println!("error: {:?}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(None) => (),
+        Err(e) => {
+            println!("error: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let (tx, mut rx) = channel::<ItemRecord>(20);
+    let (datastore, four) = Datastore::spawn(store::open(&config.history).expect("no save"));
+    let five = datastore.prune_every(config.history.retention.clone());
+    let six = tokio::spawn(track(Arc::clone(&config), datastore.clone()));
 
     let one: JoinHandle<AnyResult<()>> = tokio::spawn({
         let txx = tx.clone();
         let local_config = Arc::clone(&config);
+        let local_datastore = datastore.clone();
         async move {
-            RssWatcher::new(local_config, txx, tx_check).start().await?;
+            RssWatcher::new(local_config, txx, local_datastore)
+                .start()
+                .await?;
             AnyOk(())
         }
     });
     let two: JoinHandle<AnyResult<()>> = tokio::spawn({
         let local_config = Arc::clone(&config);
+        let local_datastore = datastore.clone();
 
         async {
-            if let Err(e) = async_watch(local_config).await {
+            if let Err(e) = async_watch(local_config, local_datastore).await {
                 println!("error: {:?}", e)
             }
             AnyOk(())
@@ -57,81 +97,203 @@ async fn main() {
         let local_config = Arc::clone(&config);
 
         async move {
-            let trans_client = RpcClient::new(local_config);
-
-            loop {
-                let link = rx.recv().await;
-                {
-                    if let Ok(s) = link {
-                        let action = Add(FileName(s));
-                        trans_client.request(action.to_action(), None).await?;
+            let trans_client = RpcClient::new(Arc::clone(&local_config));
+            let hooks = Hooks::new(&local_config.hooks);
+
+            // Bounded, so a slow Transmission holds the RSS task back rather
+            // than items being dropped.
+            while let Some(record) = rx.recv().await {
+                let link = record.link.clone();
+                let (status, added) = trans_client.add(&record.link).await;
+                // Failed adds are sent again, so only an accepted one counts as a grab.
+                if status == Status::Submitted {
+                    let grabbed = LifecycleEvent::new(&link, Stage::Grabbed);
+                    if let Err(e) = datastore.add_event(grabbed).await {
+                        println!("could not record grab of {:?}: {:?}", link, e)
                     }
                 }
-            }
-        }
-    });
-
-    let mut save_base = Client::new(PathBuf::from("./save"));
-    save_base.restore().expect("no save");
-    let save = RefCell::new(save_base);
-    let four: JoinHandle<AnyResult<()>> = tokio::spawn({
-        async move {
-            loop {
-                if let Some((s, sender)) = rx_check.recv().await {
-                    let _ = sender.send(check::<RssSave>(&s, &save));
+                if let Err(e) = datastore.confirm(record, status).await {
+                    println!("could not record {:?} as {:?}: {:?}", link, status, e)
+                }
+                if let Some(added) = added {
+                    let event = LifecycleEvent {
+                        torrent_id: Some(added.id),
+                        hash: Some(added.hash_string.clone()),
+                        ..LifecycleEvent::new(&link, Stage::Added)
+                    };
+                    if let Err(e) = datastore.add_event(event).await {
+                        println!("could not record {:?} as added: {:?}", link, e)
+                    }
+                    let torrent = TorrentRecord {
+                        link: link.clone(),
+                        torrent_id: Some(added.id),
+                        hash: Some(added.hash_string),
+                        name: Some(added.name),
+                        added_at: now(),
+                    };
+                    if let Err(e) = datastore.add_torrent(torrent).await {
+                        println!("could not record torrent for {:?}: {:?}", link, e)
+                    }
+                }
+                if status == Status::Submitted {
+                    let payload = HookPayload {
+                        link: Some(link),
+                        ..Default::default()
+                    };
+                    hooks.fire(HookEvent::Grab, &payload).await;
                 }
             }
+            AnyOk(())
         }
     });
 
-    join_all(vec![one, two, three, four]).await;
+    join_all(vec![one, two, three, four, five, six]).await;
 }
 
-fn async_watcher() -> notify::Result<(RecommendedWatcher, ms_Receiver<Vec<PathBuf>>)> {
-    let (tx, rx) = ms_channel(16);
-
-    // Automatically select the best implementation for your platform.
-    // You can also access each implementation directly e.g. INotifyWatcher.
-    let watcher = RecommendedWatcher::new(
-        move |res: NResult<Event>| {
-            let local = res.unwrap_or_default();
-            println!("{:?}", local);
-            let all_exist = local.paths.iter().all(|part| part.exists());
-            match (all_exist, local.kind, local.paths) {
-                (true, EventKind::Access(_), _) => (),
-                (true, EventKind::Create(_), paths) => {
-                    let _ = futures::executor::block_on(async { tx.send(paths).await });
-                }
-                (true, EventKind::Modify(_), paths) => {
-                    let _ = futures::executor::block_on(async { tx.send(paths).await });
-                }
-                (true, EventKind::Remove(_), _) => (),
-                (true, EventKind::Other, _) => (),
-                (true, EventKind::Any, _) => (),
-                _ => (),
-            };
-        },
-        Config::default(),
-    )?;
+async fn async_watch(config: Arc<MovieConfig>, datastore: Datastore) -> AnyResult<()> {
+    let journal = Arc::new(Journal::open(config.journal_path().to_path_buf())?);
+    let units = Units::load(config.units_path().to_path_buf())?;
+    let (tx, rx) = unbounded_channel();
+    // All files and directories at each watch path and below are monitored.
+    // Dropping a watcher stops it, so they have to live as long as this loop.
+    let _watchers = config
+        .watch_paths()
+        .iter()
+        .map(|watch| async_watcher(watch, tx.clone()))
+        .collect::<notify::Result<Vec<_>>>()?;
 
-    Ok((watcher, rx))
-}
+    // Pick up anything that finished while we were not running.
+    tokio::spawn(reconcile(Arc::clone(&config), units.clone(), tx));
 
-async fn async_watch(config: Arc<MovieConfig>) -> notify::Result<()> {
-    let (mut watcher, mut rx) = async_watcher()?;
+    // Only entries that have stopped changing come out of the stabiliser,
+    // each one exactly once.
+    let mut ready = Stabiliser::new(Arc::clone(&config), units.clone()).spawn(rx);
 
-    // Add a path to be watched. All files and directories at that path and
-    // below will be monitored for changes.
-    watcher.watch(config.watch_path.as_ref(), RecursiveMode::Recursive)?;
+    // Copies can take minutes, so they run on the blocking pool, a few at a time.
+    let workers = Arc::new(Semaphore::new(config.import_workers.max(1)));
+    let notifier = Arc::new(Notifier::new(&config));
+    let hooks = Arc::new(Hooks::new(&config.hooks));
+    let refresher = Arc::new(LibraryRefresher::new(&config.media_servers));
 
-    while let Some(res) = rx.recv().await {
-        println!("changed: {:?}", res);
+    while let Some((path, watch)) = ready.recv().await {
+        println!("settled: {:?}", path);
 
-        res.iter().for_each(|path| {
-            let local_config = Arc::clone(&config);
-            copy_file(path, local_config).ok().unwrap_or_default()
+        let permit = Arc::clone(&workers).acquire_owned().await?;
+        let local_config = Arc::clone(&config);
+        let local_journal = Arc::clone(&journal);
+        let local_notifier = Arc::clone(&notifier);
+        let local_hooks = Arc::clone(&hooks);
+        let local_refresher = Arc::clone(&refresher);
+        let local_datastore = datastore.clone();
+        let local_units = units.clone();
+        tokio::spawn(async move {
+            let joined = spawn_blocking({
+                let path = path.clone();
+                move || {
+                    let release = release_info(&path);
+                    (
+                        copy_file(&path, &watch, &local_config, &local_journal),
+                        release,
+                    )
+                }
+            })
+            .await;
+            drop(permit);
+            // A panic loses the release info too, but the failure hooks still run.
+            let (result, release) = match joined {
+                Ok((result, release)) => (Ok(result), Some(release)),
+                Err(e) => (Err(e), None),
+            };
+            let mut payload = HookPayload {
+                source: Some(path.clone()),
+                release,
+                ..Default::default()
+            };
+            let state = match result {
+                Ok(Ok(Outcome::Imported { location, warnings })) => {
+                    println!("imported {:?} to {:?}", path, location);
+                    payload.destination = Some(location.clone());
+                    record_import(&local_datastore, &path, &location).await;
+                    local_refresher.refresh(&location).await;
+                    local_hooks.fire(HookEvent::Import, &payload).await;
+                    if !warnings.is_empty() {
+                        local_notifier
+                            .send(&Notification::ImportWarnings {
+                                source: path.clone(),
+                                location,
+                                warnings,
+                            })
+                            .await;
+                    }
+                    UnitState::Done
+                }
+                Ok(Ok(Outcome::Quarantined { location, reason })) => {
+                    let failed = UnitState::Quarantined(reason.clone());
+                    payload.destination = Some(location.clone());
+                    payload.reason = Some(reason.clone());
+                    local_hooks.fire(HookEvent::Failure, &payload).await;
+                    local_notifier
+                        .send(&Notification::Quarantined {
+                            source: path.clone(),
+                            location,
+                            reason,
+                        })
+                        .await;
+                    failed
+                }
+                Ok(Err(e)) => {
+                    println!("import of {:?} failed: {:?}", path, e);
+                    payload.reason = Some(e.to_string());
+                    local_hooks.fire(HookEvent::Failure, &payload).await;
+                    UnitState::Failed(e.to_string())
+                }
+                Err(e) => {
+                    println!("import of {:?} panicked: {:?}", path, e);
+                    payload.reason = Some(e.to_string());
+                    local_hooks.fire(HookEvent::Failure, &payload).await;
+                    UnitState::Failed(e.to_string())
+                }
+            };
+            local_units.transition(&path, state);
         });
     }
 
     Ok(())
 }
+
+/// Links the import to the torrent, and through it the RSS item, it came from.
+async fn record_import(datastore: &Datastore, source: &Path, destination: &Path) {
+    let torrent = source
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let found = match datastore.find_torrent(&torrent).await {
+        Ok(found) => found,
+        Err(e) => {
+            println!("could not look up torrent {:?}: {:?}", torrent, e);
+            None
+        }
+    };
+    if let Some(found) = &found {
+        let event = LifecycleEvent {
+            torrent_id: found.torrent_id,
+            hash: found.hash.clone(),
+            path: Some(destination.to_path_buf()),
+            ..LifecycleEvent::new(&found.link, Stage::Imported)
+        };
+        if let Err(e) = datastore.add_event(event).await {
+            println!("could not record import of {:?}: {:?}", found.link, e)
+        }
+    }
+    let link = found.map(|found| found.link);
+    let import = ImportRecord {
+        torrent,
+        link,
+        source: source.to_path_buf(),
+        destination: destination.to_path_buf(),
+        imported_at: now(),
+    };
+    if let Err(e) = datastore.add_import(import).await {
+        println!("could not record import of {:?}: {:?}", source, e)
+    }
+}
@@ -0,0 +1,216 @@
+use crate::config::types::{MediaServer, MediaServerConfig};
+use anyhow::{Ok as AnyOk, Result as AnyResult};
+use reqwest::Client;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A server that does not answer within this long is skipped for this import.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Asks each configured media server to rescan exactly what was imported.
+pub struct LibraryRefresher {
+    servers: Vec<MediaServerConfig>,
+    common_client: Client,
+}
+
+impl LibraryRefresher {
+    pub fn new(servers: &[MediaServerConfig]) -> Self {
+        Self {
+            servers: servers.to_vec(),
+            common_client: Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Failures are logged per server, one being down does not stop the others.
+    pub async fn refresh(&self, imported: &Path) {
+        // Whether the import is a file can only be told here, not on the server.
+        let directory = scan_dir(imported);
+        for server in &self.servers {
+            let path = map_path(server, imported);
+            let directory = map_path(server, directory);
+            if let Err(e) = self.refresh_one(&server.server, &path, &directory).await {
+                println!(
+                    "could not refresh {:?} for {:?}: {:?}",
+                    server.server, path, e
+                )
+            }
+        }
+    }
+
+    /// `directory` is `path`, or its parent when a single file was imported.
+    async fn refresh_one(
+        &self,
+        server: &MediaServer,
+        path: &Path,
+        directory: &Path,
+    ) -> AnyResult<()> {
+        let request = match server {
+            MediaServer::Jellyfin { url, api_key } | MediaServer::Emby { url, api_key } => self
+                .common_client
+                .post(format!(
+                    "{}/Library/Media/Updated",
+                    url.trim_end_matches('/')
+                ))
+                .header("X-Emby-Token", api_key)
+                .json(&json!({
+                    "Updates": [{ "Path": path.to_string_lossy(), "UpdateType": "Created" }]
+                })),
+            MediaServer::Plex {
+                url,
+                token,
+                section_id,
+            } => self
+                .common_client
+                .get(format!(
+                    "{}/library/sections/{}/refresh",
+                    url.trim_end_matches('/'),
+                    section_id
+                ))
+                .header("X-Plex-Token", token)
+                .query(&[("path", directory.to_string_lossy())]),
+            MediaServer::Kodi {
+                url,
+                user,
+                password,
+            } => {
+                // Kodi only scans directories and wants them with a trailing slash.
+                let mut directory = directory.to_string_lossy().to_string();
+                if !directory.ends_with('/') {
+                    directory.push('/');
+                }
+                let request = self
+                    .common_client
+                    .post(format!("{}/jsonrpc", url.trim_end_matches('/')))
+                    .json(&json!({
+                        "jsonrpc": "2.0",
+                        "method": "VideoLibrary.Scan",
+                        "params": { "directory": directory },
+                        "id": 1
+                    }));
+                match user {
+                    Some(user) => request.basic_auth(user, password.as_ref()),
+                    None => request,
+                }
+            }
+        };
+        request.send().await?.error_for_status()?;
+        AnyOk(())
+    }
+}
+
+/// Plex and Kodi scan folders, so a single imported file scans its parent.
+fn scan_dir(path: &Path) -> &Path {
+    if path.is_file() {
+        path.parent().unwrap_or(path)
+    } else {
+        path
+    }
+}
+
+fn map_path(server: &MediaServerConfig, path: &Path) -> PathBuf {
+    match (&server.path_from, &server.path_to) {
+        (Some(from), Some(to)) => match path.strip_prefix(from) {
+            Ok(rest) => Path::new(to).join(rest),
+            Err(_) => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, reply, ScratchDir};
+    use std::fs;
+    use tokio::task::JoinHandle;
+
+    /// Accepts one request, answers 204 and hands back the raw request text.
+    async fn mock_server() -> (String, JoinHandle<String>) {
+        let (url, requests) = testing::mock_server(vec![reply("204 No Content", "", "")]).await;
+        let handle = tokio::spawn(async move { requests.await.unwrap().remove(0) });
+        (url, handle)
+    }
+
+    fn config(server: MediaServer) -> MediaServerConfig {
+        MediaServerConfig {
+            server,
+            path_from: Some("/mnt".to_string()),
+            path_to: Some("/media".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn jellyfin_posts_the_imported_path() {
+        let (url, request) = mock_server().await;
+        let refresher = LibraryRefresher::new(&[config(MediaServer::Jellyfin {
+            url,
+            api_key: "key".to_string(),
+        })]);
+        refresher.refresh(Path::new("/mnt/tv/show/01")).await;
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /Library/Media/Updated "));
+        assert!(request.to_lowercase().contains("x-emby-token: key"));
+        assert!(request.contains(r#""Path":"/media/tv/show/01""#));
+    }
+
+    #[tokio::test]
+    async fn plex_requests_a_partial_scan() {
+        let (url, request) = mock_server().await;
+        let refresher = LibraryRefresher::new(&[config(MediaServer::Plex {
+            url,
+            token: "tok".to_string(),
+            section_id: 2,
+        })]);
+        refresher.refresh(Path::new("/mnt/movie/Film")).await;
+        let request = request.await.unwrap();
+        assert!(
+            request.starts_with("GET /library/sections/2/refresh?path=%2Fmedia%2Fmovie%2FFilm ")
+        );
+        assert!(request.to_lowercase().contains("x-plex-token: tok"));
+    }
+
+    #[tokio::test]
+    async fn a_single_file_scans_its_mapped_parent() {
+        let dir = ScratchDir::new("mediaserver");
+        let file = dir.join("movie/Film.2020.mkv");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "movie").unwrap();
+        let (url, request) = mock_server().await;
+        let refresher = LibraryRefresher::new(&[MediaServerConfig {
+            server: MediaServer::Kodi {
+                url,
+                user: None,
+                password: None,
+            },
+            path_from: Some(dir.path().to_string_lossy().to_string()),
+            path_to: Some("/media".to_string()),
+        }]);
+        refresher.refresh(&file).await;
+        let request = request.await.unwrap();
+        assert!(
+            request.contains(r#""directory":"/media/movie/""#),
+            "{}",
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn kodi_scans_the_directory() {
+        let (url, request) = mock_server().await;
+        let refresher = LibraryRefresher::new(&[config(MediaServer::Kodi {
+            url,
+            user: Some("kodi".to_string()),
+            password: Some("pw".to_string()),
+        })]);
+        refresher.refresh(Path::new("/mnt/tv/show/01")).await;
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /jsonrpc "));
+        assert!(request.to_lowercase().contains("authorization: basic"));
+        assert!(request.contains(r#""method":"VideoLibrary.Scan""#));
+        assert!(request.contains(r#""directory":"/media/tv/show/01/""#));
+    }
+}
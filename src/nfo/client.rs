@@ -0,0 +1,289 @@
+use crate::config::path_functions::{episode_number, ReleaseInfo};
+use crate::config::permissions;
+use crate::config::types::Permissions;
+use crate::rss::metadata::ItemMetadata;
+use regex::Regex;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+const VIDEO_EXTENSIONS: [&str; 8] = ["mkv", "mp4", "m4v", "avi", "ts", "wmv", "mov", "webm"];
+
+/// Writes Kodi NFO files for something just imported to `location`. NFOs that
+/// already exist are left alone. Problems come back as warnings, like
+/// `permissions::apply`, since missing metadata should not fail an import.
+pub fn write(
+    location: &Path,
+    info: &ReleaseInfo,
+    item: Option<&ItemMetadata>,
+    permissions: &Permissions,
+) -> Vec<String> {
+    let mut warnings = vec![];
+    for (nfo, body) in documents(location, info, item) {
+        match create(&nfo, &body) {
+            Ok(true) => warnings.extend(permissions::apply(&nfo, permissions, false)),
+            Ok(false) => (),
+            Err(e) => warnings.push(format!("could not write {:?}: {}", nfo, e)),
+        }
+    }
+    warnings
+}
+
+/// Only creates, never truncates, so a hand edited NFO survives a reimport.
+fn create(nfo: &Path, body: &str) -> std::io::Result<bool> {
+    match OpenOptions::new().write(true).create_new(true).open(nfo) {
+        Ok(mut file) => {
+            file.write_all(body.as_bytes())?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn documents(
+    location: &Path,
+    info: &ReleaseInfo,
+    item: Option<&ItemMetadata>,
+) -> Vec<(PathBuf, String)> {
+    let plot = item.and_then(|item| item.description.as_deref());
+    let date = item
+        .and_then(|item| item.pub_date.as_deref())
+        .and_then(rfc2822_date);
+    if info.movie {
+        let name = item
+            .and_then(|item| item.title.as_deref())
+            .unwrap_or(&info.name);
+        let nfo = if location.is_dir() {
+            location.join("movie.nfo")
+        } else {
+            location.with_extension("nfo")
+        };
+        let mut fields = vec![("title", clean_title(name))];
+        fields.extend(year(name).map(|year| ("year", year)));
+        fields.extend(plot.map(|plot| ("plot", plot.to_string())));
+        fields.extend(date.map(|date| ("premiered", date)));
+        return vec![(nfo, document("movie", &fields))];
+    }
+
+    let show = info.show.as_deref().map(clean_title).unwrap_or_default();
+    let (show_dir, episodes) = if location.is_dir() {
+        (location.parent(), videos(location))
+    } else {
+        (
+            location.parent().and_then(Path::parent),
+            vec![location.to_path_buf()],
+        )
+    };
+    // A season pack came from one RSS item, which says nothing about each episode.
+    let item = item.filter(|_| episodes.len() == 1);
+    let plot = item.and_then(|item| item.description.as_deref());
+    let date = item
+        .and_then(|item| item.pub_date.as_deref())
+        .and_then(rfc2822_date);
+    let mut documents = vec![];
+    if let Some(show_dir) = show_dir {
+        documents.push((
+            show_dir.join("tvshow.nfo"),
+            document("tvshow", &[("title", show.clone())]),
+        ));
+    }
+    for episode in episodes {
+        let number = episode_number(&episode).or(info.episode);
+        let title = match (item.and_then(|item| item.title.clone()), number) {
+            (Some(title), _) => title,
+            (None, Some(number)) => format!("Episode {}", number),
+            (None, None) => episode
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        };
+        let mut fields = vec![("title", title), ("showtitle", show.clone())];
+        fields.extend(info.season.map(|season| ("season", season.to_string())));
+        fields.extend(number.map(|number| ("episode", number.to_string())));
+        fields.extend(plot.map(|plot| ("plot", plot.to_string())));
+        fields.extend(date.clone().map(|date| ("aired", date)));
+        documents.push((
+            episode.with_extension("nfo"),
+            document("episodedetails", &fields),
+        ));
+    }
+    documents
+}
+
+fn document(root: &str, fields: &[(&str, String)]) -> String {
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\" ?>\n");
+    body.push_str(&format!("<{}>\n", root));
+    for (name, value) in fields {
+        body.push_str(&format!("    <{0}>{1}</{0}>\n", name, escape(value)));
+    }
+    body.push_str(&format!("</{}>\n", root));
+    body
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn videos(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.extension()
+                        .map(|ext| ext.to_string_lossy().to_lowercase())
+                        .is_some_and(|ext| VIDEO_EXTENSIONS.contains(&ext.as_str()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn year(name: &str) -> Option<String> {
+    let pattern = Regex::new(r"\b(19\d{2}|20\d{2})\b").unwrap();
+    Some(pattern.captures(name)?.get(1)?.as_str().to_string())
+}
+
+/// `the.show_name` becomes `The Show Name`, and a release name is cut at its
+/// season marker or year.
+fn clean_title(name: &str) -> String {
+    let cut = Regex::new(r"[sS]\d{2}|\(?\b(19|20)\d{2}\b").unwrap();
+    let name = match cut.find(name) {
+        Some(found) if found.start() > 0 => &name[..found.start()],
+        _ => name,
+    };
+    name.split(['.', '_', ' '])
+        .filter(|word| !word.is_empty() && *word != "-")
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// RSS dates look like `Tue, 10 Jun 2003 04:00:00 GMT`, Kodi wants `2003-06-10`.
+fn rfc2822_date(date: &str) -> Option<String> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let mut parts = date.split(',').next_back()?.split_whitespace();
+    let day: u8 = parts.next()?.parse().ok()?;
+    let month = parts.next()?.to_lowercase();
+    let month = MONTHS.iter().position(|m| month.starts_with(m))? + 1;
+    let year: u16 = parts.next()?.parse().ok()?;
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    fn episode_info(name: &str) -> ReleaseInfo {
+        ReleaseInfo {
+            movie: false,
+            show: Some("the.show".to_string()),
+            season: Some(1),
+            episode: None,
+            name: name.to_string(),
+        }
+    }
+
+    fn item(title: &str) -> ItemMetadata {
+        ItemMetadata {
+            title: Some(title.to_string()),
+            description: Some("Things & <stuff>".to_string()),
+            pub_date: Some("Tue, 10 Jun 2003 04:00:00 GMT".to_string()),
+            link: None,
+            recorded: None,
+        }
+    }
+
+    #[test]
+    fn titles_are_cleaned_and_dates_converted() {
+        assert_eq!(clean_title("the.show_name"), "The Show Name");
+        assert_eq!(clean_title("Some.Movie.2020.1080p"), "Some Movie");
+        assert_eq!(clean_title("The Show - S01E02"), "The Show");
+        assert_eq!(
+            rfc2822_date("Tue, 10 Jun 2003 04:00:00 GMT").as_deref(),
+            Some("2003-06-10")
+        );
+        assert_eq!(rfc2822_date("yesterday"), None);
+    }
+
+    #[test]
+    fn a_single_episode_uses_the_feed_item() {
+        let dir = ScratchDir::new("nfo-episode");
+        let season = dir.join("tv/the.show/01");
+        fs::create_dir_all(&season).unwrap();
+        let file = season.join("The.Show.S01E02.mkv");
+        let docs = documents(
+            &file,
+            &episode_info("The.Show.S01E02.mkv"),
+            Some(&item("Pilot")),
+        );
+
+        assert_eq!(docs[0].0, dir.join("tv/the.show/tvshow.nfo"));
+        assert!(docs[0].1.contains("<title>The Show</title>"));
+        assert_eq!(docs[1].0, season.join("The.Show.S01E02.nfo"));
+        let body = &docs[1].1;
+        assert!(body.contains("<title>Pilot</title>"), "{}", body);
+        assert!(body.contains("<episode>2</episode>"));
+        assert!(body.contains("<plot>Things &amp; &lt;stuff&gt;</plot>"));
+        assert!(body.contains("<aired>2003-06-10</aired>"));
+    }
+
+    #[test]
+    fn season_packs_ignore_the_feed_item() {
+        let dir = ScratchDir::new("nfo-pack");
+        let season = dir.join("tv/the.show/01");
+        fs::create_dir_all(&season).unwrap();
+        fs::write(season.join("The.Show.S01E01.mkv"), "").unwrap();
+        fs::write(season.join("The.Show.S01E02.mkv"), "").unwrap();
+        fs::write(season.join("notes.txt"), "").unwrap();
+        let docs = documents(&season, &episode_info("The.Show.S01"), Some(&item("Pack")));
+
+        let mut episodes: Vec<&String> = docs[1..].iter().map(|(_, body)| body).collect();
+        episodes.sort();
+        assert_eq!(episodes.len(), 2);
+        assert!(episodes[0].contains("<title>Episode 1</title>"));
+        assert!(episodes[1].contains("<title>Episode 2</title>"));
+        assert!(episodes.iter().all(|body| !body.contains("<plot>")));
+    }
+
+    #[test]
+    fn movies_get_a_year_and_existing_nfos_are_kept() {
+        let dir = ScratchDir::new("nfo-movie");
+        let file = dir.join("Some.Movie.2020.mkv");
+        let info = ReleaseInfo {
+            movie: true,
+            show: None,
+            season: None,
+            episode: None,
+            name: "Some.Movie.2020.mkv".to_string(),
+        };
+        assert!(write(&file, &info, None, &Permissions::default()).is_empty());
+        let body = fs::read_to_string(dir.join("Some.Movie.2020.nfo")).unwrap();
+        assert!(body.contains("<title>Some Movie</title>"));
+        assert!(body.contains("<year>2020</year>"));
+
+        fs::write(dir.join("Some.Movie.2020.nfo"), "edited").unwrap();
+        assert!(write(&file, &info, Some(&item("Other")), &Permissions::default()).is_empty());
+        assert_eq!(
+            fs::read_to_string(dir.join("Some.Movie.2020.nfo")).unwrap(),
+            "edited"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use crate::config::types::MovieConfig;
+use reqwest::Client;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Things worth telling a human about, posted as JSON to every configured URL.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Notification {
+    Quarantined {
+        #[serde(with = "crate::config::raw_path")]
+        source: PathBuf,
+        #[serde(with = "crate::config::raw_path")]
+        location: PathBuf,
+        reason: String,
+    },
+    /// The import went through but some ownership or mode changes did not.
+    ImportWarnings {
+        #[serde(with = "crate::config::raw_path")]
+        source: PathBuf,
+        #[serde(with = "crate::config::raw_path")]
+        location: PathBuf,
+        warnings: Vec<String>,
+    },
+}
+
+pub struct Notifier {
+    urls: Vec<String>,
+    common_client: Client,
+}
+
+impl Notifier {
+    pub fn new(config: &MovieConfig) -> Self {
+        Self {
+            urls: config.notifications.clone(),
+            common_client: Client::new(),
+        }
+    }
+
+    /// Delivery is best effort, failures are only logged.
+    pub async fn send(&self, notification: &Notification) {
+        println!("notification: {:?}", notification);
+        for url in &self.urls {
+            let res = self
+                .common_client
+                .post(url)
+                .json(notification)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status());
+            if let Err(err) = res {
+                println!("could not notify {}: {:?}", url, err)
+            }
+        }
+    }
+}
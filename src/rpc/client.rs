@@ -1,6 +1,9 @@
-use super::methods::RpcAction;
+use super::methods::AddType::FileName;
+use super::methods::TorrentActions::{Add, Get};
+use super::methods::{AddedTorrent, RpcAction, RpcResponse, TorrentState};
 use crate::config::types::Auth::Basic;
 use crate::config::types::MovieConfig;
+use crate::datastore::record::Status;
 use anyhow::bail;
 use anyhow::Ok as AnyOk;
 use anyhow::Result as AnyResult;
@@ -73,4 +76,111 @@ impl RpcClient {
             Ok(test) => AnyOk(test.text().await?),
         }
     }
+
+    /// Adds a torrent by URL and reports how it went, for the history store,
+    /// along with the torrent Transmission made of it.
+    pub async fn add(&self, link: &str) -> (Status, Option<AddedTorrent>) {
+        let body = match self
+            .request(Add(FileName(link.to_string())).to_action(), None)
+            .await
+        {
+            Ok(body) => body,
+            Err(_) => return (Status::Failed, None),
+        };
+        match serde_json::from_str::<RpcResponse>(&body) {
+            Ok(response) if response.result == "success" => {
+                let arguments = response.arguments;
+                (Status::Submitted, arguments.added.or(arguments.duplicate))
+            }
+            Ok(response) => {
+                println!("transmission refused {}: {}", link, response.result);
+                (Status::Rejected, None)
+            }
+            Err(e) => {
+                println!("unexpected reply adding {}: {:?}: {}", link, e, body);
+                (Status::Failed, None)
+            }
+        }
+    }
+
+    /// What Transmission has to say about the torrents with these hashes.
+    /// Torrents it no longer has are simply missing from the answer.
+    pub async fn torrents(&self, hashes: Vec<String>) -> AnyResult<Vec<TorrentState>> {
+        let body = self.request(Get(Some(hashes)).to_action(), None).await?;
+        let response = serde_json::from_str::<RpcResponse>(&body)?;
+        if response.result != "success" {
+            bail!("transmission refused torrent-get: {}", response.result)
+        }
+        AnyOk(response.arguments.torrents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, mock_server, reply};
+    use serde_json::json;
+
+    fn client(url: &str) -> RpcClient {
+        RpcClient::new(Arc::new(config(json!({
+            "rss": { "feed": "http://feed", "dest": url, "auth": "None" },
+        }))))
+    }
+
+    fn answer(body: serde_json::Value) -> String {
+        reply(
+            "200 OK",
+            "Content-Type: application/json\r\n",
+            &body.to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn accepted_torrents_are_submitted_after_the_session_handshake() {
+        let torrent = json!({ "id": 7, "hashString": "abc", "name": "The.Show.S01E02" });
+        let (url, requests) = mock_server(vec![
+            reply("409 Conflict", "X-Transmission-Session-Id: s1\r\n", ""),
+            answer(json!({ "result": "success", "arguments": { "torrent-added": torrent } })),
+        ])
+        .await;
+        let (status, added) = client(&url).add("http://x/1.torrent").await;
+        assert_eq!(status, Status::Submitted);
+        let added = added.unwrap();
+        assert_eq!((added.id, added.hash_string.as_str()), (7, "abc"));
+
+        let requests = requests.await.unwrap();
+        assert!(requests[1]
+            .to_lowercase()
+            .contains("x-transmission-session-id: s1"));
+        assert!(requests[1].contains(r#""method":"torrent-add""#));
+        assert!(requests[1].contains("http://x/1.torrent"));
+    }
+
+    #[tokio::test]
+    async fn duplicates_count_as_submitted() {
+        let torrent = json!({ "id": 3, "hashString": "def", "name": "Other" });
+        let (url, _) = mock_server(vec![answer(json!({
+            "result": "success",
+            "arguments": { "torrent-duplicate": torrent },
+        }))])
+        .await;
+        let (status, added) = client(&url).add("http://x/2.torrent").await;
+        assert_eq!(status, Status::Submitted);
+        assert_eq!(added.unwrap().id, 3);
+    }
+
+    #[tokio::test]
+    async fn refusals_are_rejected_and_garbage_or_no_answer_failed() {
+        let (url, _) = mock_server(vec![
+            answer(json!({ "result": "invalid or corrupt torrent file" })),
+            reply("200 OK", "", "<html>proxy error</html>"),
+        ])
+        .await;
+        let client = client(&url);
+        assert_eq!(client.add("http://x/3").await.0, Status::Rejected);
+        assert_eq!(client.add("http://x/4").await.0, Status::Failed);
+
+        let (status, added) = self::client("http://127.0.0.1:1").add("http://x/5").await;
+        assert_eq!((status, added.is_none()), (Status::Failed, true));
+    }
 }
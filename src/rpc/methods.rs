@@ -1,18 +1,28 @@
 use serde::{Deserialize, Serialize};
 
 pub enum AddType {
+    /// Base64 torrent file contents. Nothing adds by contents yet.
+    #[allow(dead_code)]
     Meta(String),
     FileName(String),
 }
 
+/// RPC methods the client knows how to build. Only `Get` and `Add` are sent
+/// so far; the rest are kept so the request shapes stay in one place.
 pub enum TorrentActions {
+    #[allow(dead_code)]
     Start(Option<Vec<String>>),
+    #[allow(dead_code)]
     StartNow(Option<Vec<String>>),
+    #[allow(dead_code)]
     Stop(Option<Vec<String>>),
+    #[allow(dead_code)]
     Verify(Option<Vec<String>>),
+    #[allow(dead_code)]
     Reannounce(Option<Vec<String>>),
+    #[allow(dead_code)]
     Set,
-    Get,
+    Get(Option<Vec<String>>),
     Add(AddType),
 }
 
@@ -20,6 +30,8 @@ pub enum TorrentActions {
 enum Feilds {
     #[serde(rename = "id")]
     Id,
+    #[serde(rename = "hashString")]
+    HashString,
     #[serde(rename = "error")]
     Error,
     #[serde(rename = "errorString")]
@@ -90,6 +102,8 @@ impl Arguments {
                 Feilds::IsFinished,
                 Feilds::QueuePosition,
                 Feilds::PercentDone,
+                Feilds::HashString,
+                Feilds::LeftUntilDone,
             ]),
             ..Self::default()
         }
@@ -114,6 +128,52 @@ impl Arguments {
     }
 }
 
+/// The envelope of every RPC reply. `result` is `success` or an error message.
+#[derive(Deserialize, Debug)]
+pub struct RpcResponse {
+    pub result: String,
+    #[serde(default)]
+    pub arguments: ResponseArguments,
+}
+
+/// `torrent-add` answers with `added` or `duplicate`, depending on whether
+/// Transmission already had the torrent, and `torrent-get` with `torrents`.
+#[derive(Deserialize, Debug, Default)]
+pub struct ResponseArguments {
+    #[serde(rename = "torrent-added")]
+    pub added: Option<AddedTorrent>,
+    #[serde(rename = "torrent-duplicate")]
+    pub duplicate: Option<AddedTorrent>,
+    #[serde(default)]
+    pub torrents: Vec<TorrentState>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AddedTorrent {
+    pub id: i64,
+    #[serde(rename = "hashString")]
+    pub hash_string: String,
+    pub name: String,
+}
+
+/// The part of a `torrent-get` entry the lifecycle tracking needs.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TorrentState {
+    pub id: i64,
+    #[serde(rename = "hashString")]
+    pub hash_string: String,
+    #[serde(rename = "leftUntilDone", default)]
+    pub left_until_done: i64,
+    #[serde(rename = "percentDone", default)]
+    pub percent_done: f64,
+}
+
+impl TorrentState {
+    pub fn is_complete(&self) -> bool {
+        self.left_until_done == 0 && self.percent_done >= 1.0
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct RpcAction {
     method: String,
@@ -150,9 +210,9 @@ impl TorrentActions {
                 method: "".to_string(),
                 arguments: Arguments::standard(),
             },
-            Self::Get => RpcAction {
-                method: "".to_string(),
-                arguments: Arguments::standard(),
+            Self::Get(id) => RpcAction {
+                method: "torrent-get".to_string(),
+                arguments: Arguments::new(id),
             },
             Self::Add(action_type) => RpcAction {
                 method: "torrent-add".to_string(),
@@ -0,0 +1,151 @@
+use super::client::RpcClient;
+use super::methods::TorrentState;
+use crate::config::types::MovieConfig;
+use crate::datastore::handle::Datastore;
+use crate::datastore::record::{LifecycleEvent, Stage};
+use anyhow::Result as AnyResult;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::time::interval;
+
+/// What an item moves on to, given what Transmission says about its torrent.
+/// Transmission answers with nothing while it is still loading its torrents,
+/// so a torrent only counts as removed once it was also missing last poll.
+fn next_stage(current: Stage, state: Option<&TorrentState>, missed: bool) -> Option<Stage> {
+    match state {
+        None => missed.then_some(Stage::Removed),
+        Some(state) if state.is_complete() => {
+            matches!(current, Stage::Added | Stage::Downloading).then_some(Stage::Completed)
+        }
+        Some(_) => (current == Stage::Added).then_some(Stage::Downloading),
+    }
+}
+
+/// `missing` holds the hashes of torrents Transmission did not know last poll.
+async fn poll(
+    client: &RpcClient,
+    datastore: &Datastore,
+    missing: &mut HashSet<String>,
+) -> AnyResult<()> {
+    let tracked: Vec<_> = datastore
+        .tracked()
+        .await?
+        .into_iter()
+        .filter(|event| event.hash.is_some())
+        .collect();
+    if tracked.is_empty() {
+        missing.clear();
+        return Ok(());
+    }
+    let hashes = tracked
+        .iter()
+        .filter_map(|event| event.hash.clone())
+        .collect();
+    let states = client.torrents(hashes).await?;
+    let mut missing_now = HashSet::new();
+    for event in tracked {
+        let hash = event.hash.as_deref().unwrap_or_default().to_lowercase();
+        let state = states
+            .iter()
+            .find(|state| state.hash_string.to_lowercase() == hash);
+        if state.is_none() {
+            missing_now.insert(hash.clone());
+        }
+        if let Some(stage) = next_stage(event.stage, state, missing.contains(&hash)) {
+            println!("{} is now {}", event.link, stage.as_str());
+            datastore
+                .add_event(LifecycleEvent {
+                    torrent_id: state.map(|state| state.id).or(event.torrent_id),
+                    hash: event.hash.clone(),
+                    ..LifecycleEvent::new(&event.link, stage)
+                })
+                .await?;
+        }
+    }
+    *missing = missing_now;
+    Ok(())
+}
+
+/// Asks Transmission about every torrent the history still thinks it has,
+/// every `torrent_poll_secs`, and records downloads starting, finishing and
+/// being removed.
+pub async fn track(config: Arc<MovieConfig>, datastore: Datastore) -> AnyResult<()> {
+    let client = RpcClient::new(Arc::clone(&config));
+    let mut ticks = interval(config.torrent_poll_interval());
+    let mut missing = HashSet::new();
+    loop {
+        ticks.tick().await;
+        if let Err(e) = poll(&client, &datastore, &mut missing).await {
+            println!("could not check torrents: {:?}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::DedupeConfig;
+    use crate::datastore::keyset::MemoryStore;
+    use crate::testing::{config, mock_server, reply};
+    use serde_json::json;
+
+    fn state(percent_done: f64) -> TorrentState {
+        TorrentState {
+            id: 7,
+            hash_string: "ab".repeat(20),
+            left_until_done: if percent_done >= 1.0 { 0 } else { 100 },
+            percent_done,
+        }
+    }
+
+    #[test]
+    fn stages_follow_what_transmission_says() {
+        let downloading = state(0.5);
+        let done = state(1.0);
+        assert_eq!(
+            next_stage(Stage::Added, Some(&downloading), false),
+            Some(Stage::Downloading)
+        );
+        assert_eq!(
+            next_stage(Stage::Downloading, Some(&downloading), false),
+            None
+        );
+        assert_eq!(
+            next_stage(Stage::Downloading, Some(&done), false),
+            Some(Stage::Completed)
+        );
+        assert_eq!(next_stage(Stage::Completed, Some(&done), false), None);
+        assert_eq!(next_stage(Stage::Downloading, None, false), None);
+        assert_eq!(
+            next_stage(Stage::Downloading, None, true),
+            Some(Stage::Removed)
+        );
+    }
+
+    #[tokio::test]
+    async fn torrents_are_removed_after_two_empty_answers() {
+        let empty = reply(
+            "200 OK",
+            "Content-Type: application/json\r\n",
+            &json!({ "result": "success", "arguments": { "torrents": [] } }).to_string(),
+        );
+        let (url, _) = mock_server(vec![empty.clone(), empty]).await;
+        let client = RpcClient::new(Arc::new(config(json!({
+            "rss": { "feed": "http://feed", "dest": url, "auth": "None" },
+        }))));
+        let (datastore, _) =
+            Datastore::spawn(Box::new(MemoryStore::in_memory(DedupeConfig::default())));
+        let added = LifecycleEvent {
+            torrent_id: Some(7),
+            hash: Some("ab".repeat(20)),
+            ..LifecycleEvent::new("http://a/1", Stage::Added)
+        };
+        datastore.add_event(added).await.unwrap();
+        let mut missing = HashSet::new();
+
+        poll(&client, &datastore, &mut missing).await.unwrap();
+        assert_eq!(datastore.tracked().await.unwrap()[0].stage, Stage::Added);
+        poll(&client, &datastore, &mut missing).await.unwrap();
+        assert!(datastore.tracked().await.unwrap().is_empty());
+    }
+}
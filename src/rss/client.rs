@@ -1,69 +1,87 @@
+use super::metadata::{ItemMetadata, MetadataLog};
 use crate::config::types::MovieConfig;
+use crate::datastore::handle::Datastore;
+use crate::datastore::record::ItemRecord;
+use crate::journal::client::now;
 use anyhow::{Ok as AnyOk, Result as AnyResult};
-use futures::stream;
-use futures::StreamExt;
 use reqwest::Response;
 use rss::Channel;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::broadcast::Sender;
-use tokio::sync::mpsc::Sender as MsSender;
-use tokio::sync::oneshot::{channel as one_shot, Sender as OneSender};
+use tokio::sync::mpsc::Sender;
+
+/// Items that cannot be grabbed are skipped, saying why.
+pub fn parse_item(url: &str, item: &rss::Item) -> Option<ItemRecord> {
+    match ItemRecord::from_item(url, item) {
+        Ok(record) => Some(record),
+        Err(e) => {
+            println!("skipping item {:?}: {:#}", item.title(), e);
+            None
+        }
+    }
+}
 
 pub struct RssWatcher {
     url: String,
-    tx: Sender<String>,
-    tx_check: MsSender<(String, OneSender<bool>)>,
+    tx: Sender<ItemRecord>,
+    datastore: Datastore,
+    metadata: Option<MetadataLog>,
 }
 
 impl RssWatcher {
-    pub fn new(
-        config: Arc<MovieConfig>,
-        sender: Sender<String>,
-        tx_check: MsSender<(String, OneSender<bool>)>,
-    ) -> Self {
+    pub fn new(config: Arc<MovieConfig>, sender: Sender<ItemRecord>, datastore: Datastore) -> Self {
         Self {
             url: String::from(config.rss_feed()),
             tx: sender,
-            tx_check,
+            datastore,
+            metadata: config.nfo.enabled.then(|| MetadataLog::new(&config.nfo)),
+        }
+    }
+    /// Keeps what the feed said about a grab so the import can write it into NFO files.
+    fn remember(&self, item: &rss::Item) {
+        if let Some(metadata) = &self.metadata {
+            if let Err(e) = metadata.record(&ItemMetadata::from(item)) {
+                println!("could not record metadata for {:?}: {:?}", item.link(), e)
+            }
+        }
+    }
+    /// Sends every item the store has not seen to the RPC task.
+    async fn grab_new(&self, url: &str, ch: &Channel) {
+        if let Err(e) = self.datastore.polled(url, now()).await {
+            println!("could not record poll of {:?}: {:?}", url, e)
+        }
+        let items: Vec<_> = ch
+            .items()
+            .iter()
+            .filter_map(|t| parse_item(url, t).map(|r| (r, t)))
+            .collect();
+        let records = items.iter().map(|(record, _)| record.clone()).collect();
+        // A store that cannot answer counts everything as seen, so a broken
+        // store does not turn into the same grabs every poll.
+        let seen = self.datastore.check(records).await.unwrap_or_else(|e| {
+            println!("could not check {:?}: {:?}", url, e);
+            vec![true; items.len()]
+        });
+        for ((record, item), seen) in items.into_iter().zip(seen) {
+            if seen {
+                println!("Didn't send this link: [{:?}]", record.link);
+                continue;
+            }
+            self.remember(item);
+            if self.tx.send(record).await.is_err() {
+                println!("the RPC task has stopped, not sending {:?}", item.link());
+                return;
+            }
         }
     }
     pub async fn start(&self) -> AnyResult<()> {
         let url = self.url.clone();
 
-        let txx = self.tx.clone();
-
         loop {
             match reqwest::get(url.as_str()).await {
                 Ok(res) => {
                     if let Ok(ch) = req_to_rss(res).await {
-                        let _ = stream::iter(
-                            ch.items().iter().filter_map(|t| t.link().map(String::from)),
-                        )
-                        .filter_map(|link| async {
-                            let (send, res) = one_shot::<bool>();
-                            self.tx_check.send((link.clone(), send)).await.unwrap_or(());
-                            match res.await {
-                                Ok(b) => {
-                                    if b {
-                                        println!("Didn't send this link: [{:?}]", link);
-
-                                        None
-                                    } else {
-                                        Some(link)
-                                    }
-                                }
-                                Err(_) => {
-                                    println!("Error didn't send this link: [{:?}]", link);
-                                    None
-                                }
-                            }
-                        })
-                        .map(|t| {
-                            txx.send(t.clone()).ok();
-                        })
-                        .collect::<Vec<()>>()
-                        .await;
+                        self.grab_new(&url, &ch).await;
                     }
                 }
                 Err(err) => {
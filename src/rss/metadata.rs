@@ -0,0 +1,196 @@
+use crate::config::atomic::write_atomic;
+use crate::config::types::NfoConfig;
+use crate::journal::client::now;
+use anyhow::{Ok as AnyOk, Result as AnyResult};
+use rss::Item;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// The parts of a grabbed RSS item worth keeping for its download.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub pub_date: Option<String>,
+    pub link: Option<String>,
+    /// When the item was grabbed, in seconds since the epoch.
+    #[serde(default)]
+    pub recorded: Option<u64>,
+}
+
+impl From<&Item> for ItemMetadata {
+    fn from(item: &Item) -> Self {
+        Self {
+            title: item.title().map(String::from),
+            description: item.description().map(String::from),
+            pub_date: item.pub_date().map(String::from),
+            link: item.link().map(String::from),
+            recorded: None,
+        }
+    }
+}
+
+/// Line delimited JSON of grabbed items, looked up again by download name at import time.
+#[derive(Debug)]
+pub struct MetadataLog {
+    file: PathBuf,
+    keep_secs: u64,
+}
+
+impl MetadataLog {
+    pub fn new(config: &NfoConfig) -> Self {
+        Self {
+            file: config.metadata_path().to_path_buf(),
+            keep_secs: config.metadata_days * 24 * 60 * 60,
+        }
+    }
+
+    /// Appends `item`, stamped with the current time, after dropping items
+    /// older than `metadataDays`.
+    pub fn record(&self, item: &ItemMetadata) -> AnyResult<()> {
+        self.prune(now())?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?;
+        let item = ItemMetadata {
+            recorded: Some(now()),
+            ..item.clone()
+        };
+        let mut line = serde_json::to_vec(&item)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        AnyOk(())
+    }
+
+    /// Rewrites the file without items recorded before `now - keep`. Items
+    /// from before timestamps were kept are stamped `now` instead.
+    fn prune(&self, now: u64) -> AnyResult<()> {
+        let Ok(file) = File::open(&self.file) else {
+            return AnyOk(());
+        };
+        let mut changed = false;
+        let mut kept = vec![];
+        for line in BufReader::new(file).lines() {
+            let Ok(mut item) = serde_json::from_str::<ItemMetadata>(&line?) else {
+                changed = true;
+                continue;
+            };
+            match item.recorded {
+                Some(at) if at.saturating_add(self.keep_secs) < now => changed = true,
+                Some(_) => kept.push(item),
+                None => {
+                    item.recorded = Some(now);
+                    changed = true;
+                    kept.push(item);
+                }
+            }
+        }
+        if changed {
+            let mut content = vec![];
+            for item in &kept {
+                content.extend(serde_json::to_vec(item)?);
+                content.push(b'\n');
+            }
+            write_atomic(&self.file, &content)?;
+        }
+        AnyOk(())
+    }
+
+    /// The newest item whose title matches `name`, ignoring case, punctuation
+    /// and a file extension.
+    pub fn find(&self, name: &str) -> Option<ItemMetadata> {
+        let file = File::open(&self.file).ok()?;
+        let wanted = [
+            normalise(name),
+            normalise(Path::new(name).file_stem()?.to_string_lossy().as_ref()),
+        ];
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<ItemMetadata>(&line).ok())
+            .filter(|item| {
+                item.title
+                    .as_deref()
+                    .is_some_and(|title| wanted.contains(&normalise(title)))
+            })
+            .last()
+    }
+}
+
+fn normalise(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+    use std::fs;
+
+    fn log(dir: &ScratchDir) -> MetadataLog {
+        MetadataLog::new(&NfoConfig {
+            enabled: true,
+            metadata_path: dir.join("metadata").to_string_lossy().to_string(),
+            metadata_days: 1,
+        })
+    }
+
+    fn item(title: &str, recorded: Option<u64>) -> ItemMetadata {
+        ItemMetadata {
+            title: Some(title.to_string()),
+            description: None,
+            pub_date: None,
+            link: None,
+            recorded,
+        }
+    }
+
+    fn titles(log: &MetadataLog) -> Vec<String> {
+        BufReader::new(File::open(&log.file).unwrap())
+            .lines()
+            .map(|line| serde_json::from_str::<ItemMetadata>(&line.unwrap()).unwrap())
+            .filter_map(|item| item.title)
+            .collect()
+    }
+
+    #[test]
+    fn items_are_found_by_download_name() {
+        let dir = ScratchDir::new("metadata-find");
+        let log = log(&dir);
+        log.record(&item("The Show S01E02 720p", None)).unwrap();
+        log.record(&item("Other.Show.S01E01", None)).unwrap();
+
+        let found = log.find("The.Show.S01E02.720p.mkv").unwrap();
+        assert_eq!(found.title.as_deref(), Some("The Show S01E02 720p"));
+        assert!(found.recorded.is_some());
+        assert!(log.find("Missing.S01E01.mkv").is_none());
+    }
+
+    #[test]
+    fn old_items_are_pruned_and_unstamped_ones_kept() {
+        let dir = ScratchDir::new("metadata-prune");
+        let log = log(&dir);
+        let lines: Vec<String> = [
+            item("old", Some(0)),
+            item("legacy", None),
+            item("fresh", Some(now())),
+        ]
+        .iter()
+        .map(|item| serde_json::to_string(item).unwrap())
+        .collect();
+        fs::write(&log.file, lines.join("\n") + "\nnot json\n").unwrap();
+
+        log.prune(now()).unwrap();
+        assert_eq!(titles(&log), ["legacy", "fresh"]);
+        assert!(log.find("legacy").unwrap().recorded.is_some());
+
+        log.prune(now() + 2 * 24 * 60 * 60).unwrap();
+        assert!(titles(&log).is_empty());
+    }
+}
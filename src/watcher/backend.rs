@@ -0,0 +1,104 @@
+use super::event::WatchEvent;
+use crate::config::types::{Backend, WatchPath};
+use notify::{
+    Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Result as NResult,
+    Watcher,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Filesystem types where inotify never sees changes made by other hosts.
+const NETWORK_FILESYSTEMS: [&str; 8] = [
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "9p",
+    "fuse.sshfs",
+    "fuse.rclone",
+];
+
+/// Runs on the notify thread, so it must never block: the channel is unbounded
+/// and a send only fails once the pipeline has shut down.
+fn event_handler(tx: UnboundedSender<WatchEvent>) -> impl FnMut(NResult<Event>) + Send + 'static {
+    move |res: NResult<Event>| {
+        let event = match res {
+            Err(e) => WatchEvent::Error(e),
+            Ok(event) if event.need_rescan() => WatchEvent::Rescan,
+            Ok(event) => {
+                let all_exist = event.paths.iter().all(|part| part.exists());
+                match (all_exist, event.kind) {
+                    (true, EventKind::Create(_) | EventKind::Modify(_)) => {
+                        WatchEvent::Changed(event.paths)
+                    }
+                    _ => return,
+                }
+            }
+        };
+        let _ = tx.send(event);
+    }
+}
+
+/// Looks up the filesystem type of the mount holding `path` in `/proc/mounts`.
+fn filesystem_type(path: &Path) -> Option<String> {
+    let path = fs::canonicalize(path).ok()?;
+    let mounts = fs::read_to_string("/proc/mounts").ok()?;
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let mount_point = parts.nth(1)?;
+            let fs_type = parts.next()?;
+            Some((PathBuf::from(mount_point), fs_type.to_string()))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .map(|(_, fs_type)| fs_type)
+}
+
+pub fn is_network_mount(path: &Path) -> bool {
+    filesystem_type(path)
+        .map(|fs_type| NETWORK_FILESYSTEMS.contains(&fs_type.as_str()))
+        .unwrap_or(false)
+}
+
+fn poll_watcher(
+    watch: &WatchPath,
+    tx: UnboundedSender<WatchEvent>,
+) -> NResult<Box<dyn Watcher + Send>> {
+    let poll_config = Config::default().with_poll_interval(watch.watcher.poll_interval());
+    let mut watcher = PollWatcher::new(event_handler(tx), poll_config)?;
+    watcher.watch(&watch.path, RecursiveMode::Recursive)?;
+    Ok(Box::new(watcher))
+}
+
+fn native_watcher(
+    watch: &WatchPath,
+    tx: UnboundedSender<WatchEvent>,
+) -> NResult<Box<dyn Watcher + Send>> {
+    let mut watcher = RecommendedWatcher::new(event_handler(tx), Config::default())?;
+    watcher.watch(&watch.path, RecursiveMode::Recursive)?;
+    Ok(Box::new(watcher))
+}
+
+/// Starts watching one watch path with its configured backend. All backends
+/// send into the same channel, so debouncing downstream is identical.
+pub fn async_watcher(
+    watch: &WatchPath,
+    tx: UnboundedSender<WatchEvent>,
+) -> NResult<Box<dyn Watcher + Send>> {
+    match watch.watcher.backend {
+        Backend::Native => native_watcher(watch, tx),
+        Backend::Poll => poll_watcher(watch, tx),
+        Backend::Auto if is_network_mount(&watch.path) => {
+            println!("{:?} is a network mount, polling it", watch.path);
+            poll_watcher(watch, tx)
+        }
+        Backend::Auto => native_watcher(watch, tx.clone()).or_else(|e| {
+            println!("native watcher failed ({:?}), falling back to polling", e);
+            poll_watcher(watch, tx)
+        }),
+    }
+}
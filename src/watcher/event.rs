@@ -0,0 +1,13 @@
+use std::path::PathBuf;
+
+/// What the watcher backends and the reconciler feed into the stabiliser.
+#[derive(Debug)]
+pub enum WatchEvent {
+    /// Something was created or modified at these paths.
+    Changed(Vec<PathBuf>),
+    /// The backend dropped events, e.g. an inotify queue overflow, so the
+    /// whole watch path has to be scanned again.
+    Rescan,
+    /// The backend reported an error instead of an event.
+    Error(notify::Error),
+}
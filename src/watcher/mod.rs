@@ -0,0 +1,5 @@
+pub mod backend;
+pub mod event;
+pub mod reconcile;
+pub mod stabilise;
+pub mod unit;
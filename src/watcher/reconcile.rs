@@ -0,0 +1,102 @@
+use super::event::WatchEvent;
+use super::unit::Units;
+use crate::config::types::{MovieConfig, WatchPath};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+fn entries_at(dir: &Path, depth: usize, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if depth > 1 {
+            if entry.file_type()?.is_dir() {
+                entries_at(&entry.path(), depth - 1, found)?;
+            }
+        } else {
+            found.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Lists the downloads in a watch path that have no settled state yet.
+pub fn scan(watch: &WatchPath, units: &Units) -> io::Result<Vec<PathBuf>> {
+    let mut found = vec![];
+    entries_at(&watch.path, watch.depth, &mut found)?;
+    found.retain(|path| !units.is_handled(path));
+    Ok(found)
+}
+
+/// How often failed units are checked for a retry when there is no rescan.
+const RETRY_TICK: Duration = Duration::from_secs(60);
+
+/// Feeds entries that arrived while the daemon was down into the same event
+/// pipeline as the watcher, once at startup and then every `rescan_secs`.
+/// Failed units are fed back in as their retry backoff runs out.
+pub async fn reconcile(config: Arc<MovieConfig>, units: Units, tx: UnboundedSender<WatchEvent>) {
+    let tick = config
+        .rescan_interval()
+        .map_or(RETRY_TICK, |every| every.min(RETRY_TICK));
+    let mut last_scan: Option<Instant> = None;
+    loop {
+        units.prune_missing();
+        let mut paths = units.take_due_retries();
+        let rescan = match (last_scan, config.rescan_interval()) {
+            (None, _) => true,
+            (Some(at), Some(every)) => at.elapsed() >= every,
+            (Some(_), None) => false,
+        };
+        if rescan {
+            last_scan = Some(Instant::now());
+            for watch in config.watch_paths() {
+                match scan(watch, &units) {
+                    Ok(found) => paths.extend(found),
+                    Err(e) => println!("could not scan {:?}: {:?}", watch.path, e),
+                }
+            }
+        }
+        paths.sort();
+        paths.dedup();
+        if !paths.is_empty() {
+            println!("reconciling: {:?}", paths);
+            if tx.send(WatchEvent::Changed(paths)).is_err() {
+                return;
+            }
+        }
+        tokio::time::sleep(tick).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, ScratchDir};
+    use crate::watcher::unit::UnitState;
+    use serde_json::json;
+
+    #[test]
+    fn scan_skips_handled_entries_at_the_watch_depth() {
+        let dir = ScratchDir::new("reconcile-scan");
+        fs::create_dir_all(dir.join("tv/Show.S01")).unwrap();
+        fs::write(dir.join("tv/Show.S01/e01.mkv"), "video").unwrap();
+        fs::write(dir.join("tv/Movie.2020.mkv"), "video").unwrap();
+        fs::write(dir.join("stray.mkv"), "video").unwrap();
+        let config = config(json!({ "watch": [{ "path": dir.path(), "depth": 2 }] }));
+        let units = Units::default();
+        let done = dir.join("tv/Movie.2020.mkv");
+        for state in [
+            UnitState::Pending,
+            UnitState::Stabilising,
+            UnitState::Importing,
+            UnitState::Done,
+        ] {
+            units.transition(&done, state);
+        }
+
+        let found = scan(&config.watch_paths()[0], &units).unwrap();
+        assert_eq!(found, vec![dir.join("tv/Show.S01")]);
+    }
+}
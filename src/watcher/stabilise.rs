@@ -0,0 +1,335 @@
+use super::event::WatchEvent;
+use super::reconcile::scan;
+use super::unit::{UnitState, Units};
+use crate::config::types::{MovieConfig, StabiliseConfig, WatchPath};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{
+    channel as ms_channel, Receiver as ms_Receiver, Sender as ms_Sender, UnboundedReceiver,
+};
+use tokio::task::spawn_blocking;
+use tokio::time::{interval, Instant};
+
+/// Size and newest mtime of an entry, summed over everything below it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Snapshot {
+    size: u64,
+    modified: Option<SystemTime>,
+    in_progress: bool,
+}
+
+#[derive(Debug)]
+struct Pending {
+    /// Index into `MovieConfig::watch_paths` of the path this unit lives in.
+    watch: usize,
+    last_change: Instant,
+    snapshot: Option<Snapshot>,
+}
+
+/// Debounces raw watcher events per download in any watch path and
+/// only releases an entry once it has stopped changing for the quiet period.
+/// Released entries are marked `Importing` in `units`, so later events for
+/// them are ignored and each is handed to the mover once.
+pub struct Stabiliser {
+    config: Arc<MovieConfig>,
+    units: Units,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+/// Maps any path below a watch path to the download that contains it, which
+/// sits `depth` levels below the watch path.
+pub fn unit_path(watch: &WatchPath, path: &Path) -> Option<PathBuf> {
+    let parts: Vec<Component> = path
+        .strip_prefix(&watch.path)
+        .ok()?
+        .components()
+        .take(watch.depth)
+        .collect();
+    if parts.len() < watch.depth || !parts.iter().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(
+        parts
+            .iter()
+            .fold(watch.path.clone(), |unit, c| unit.join(c)),
+    )
+}
+
+fn snapshot(path: &Path, stabilise: &StabiliseConfig) -> io::Result<Snapshot> {
+    let meta = fs::symlink_metadata(path)?;
+    let mut snap = Snapshot {
+        size: meta.len(),
+        modified: meta.modified().ok(),
+        in_progress: stabilise.is_in_progress(path),
+    };
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let child = snapshot(&entry?.path(), stabilise)?;
+            snap.size += child.size;
+            snap.modified = snap.modified.max(child.modified);
+            snap.in_progress |= child.in_progress;
+        }
+    }
+    Ok(snap)
+}
+
+impl Stabiliser {
+    pub fn new(config: Arc<MovieConfig>, units: Units) -> Self {
+        Self {
+            config,
+            units,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Consumes watcher events and yields downloads, with the watch path they
+    /// came from, once they are ready to move.
+    pub fn spawn(self, events: UnboundedReceiver<WatchEvent>) -> ms_Receiver<(PathBuf, WatchPath)> {
+        let (tx, rx) = ms_channel(16);
+        tokio::spawn(self.run(events, tx));
+        rx
+    }
+
+    async fn run(
+        mut self,
+        mut events: UnboundedReceiver<WatchEvent>,
+        ready: ms_Sender<(PathBuf, WatchPath)>,
+    ) {
+        let shortest = self
+            .config
+            .watch_paths()
+            .iter()
+            .map(|watch| watch.stabilise.quiet_period())
+            .min()
+            .unwrap_or_default();
+        let mut ticker = interval((shortest / 4).max(Duration::from_millis(250)));
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(WatchEvent::Changed(paths)) => self.touch(paths),
+                    Some(WatchEvent::Rescan) => self.rescan(),
+                    Some(WatchEvent::Error(e)) => {
+                        println!("watcher error: {}", e);
+                        self.touch(e.paths);
+                    }
+                    None => break,
+                },
+                _ = ticker.tick() => {
+                    for unit in self.settled().await {
+                        if ready.send(unit).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn rescan(&mut self) {
+        let config = Arc::clone(&self.config);
+        for watch in config.watch_paths() {
+            match scan(watch, &self.units) {
+                Ok(paths) => self.touch(paths),
+                Err(e) => println!("could not rescan {:?}: {:?}", watch.path, e),
+            }
+        }
+    }
+
+    /// Finds the watch path holding `path`, preferring the deepest when they nest.
+    fn locate(&self, path: &Path) -> Option<(usize, PathBuf)> {
+        self.config
+            .watch_paths()
+            .iter()
+            .enumerate()
+            .filter(|(_, watch)| path.starts_with(&watch.path))
+            .max_by_key(|(_, watch)| watch.path.components().count())
+            .and_then(|(index, watch)| Some((index, unit_path(watch, path)?)))
+    }
+
+    fn touch(&mut self, paths: Vec<PathBuf>) {
+        for path in paths {
+            if let Some((watch, unit)) = self.locate(&path) {
+                if !self.units.transition(&unit, UnitState::Pending) {
+                    continue;
+                }
+                self.pending.insert(
+                    unit,
+                    Pending {
+                        watch,
+                        last_change: Instant::now(),
+                        snapshot: None,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Snapshots every entry that has been quiet long enough. The walks run
+    /// on the blocking pool, since a season pack can hold many files.
+    async fn settled(&mut self) -> Vec<(PathBuf, WatchPath)> {
+        let quiet: Vec<(PathBuf, StabiliseConfig)> = self
+            .pending
+            .iter()
+            .filter_map(|(path, pending)| {
+                let watch = &self.config.watch_paths()[pending.watch];
+                (pending.last_change.elapsed() >= watch.stabilise.quiet_period())
+                    .then(|| (path.clone(), watch.stabilise.clone()))
+            })
+            .collect();
+        if quiet.is_empty() {
+            return vec![];
+        }
+        let snapshots = spawn_blocking(move || {
+            quiet
+                .into_iter()
+                .map(|(path, stabilise)| {
+                    let snap = snapshot(&path, &stabilise);
+                    (path, snap)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await;
+        let snapshots = match snapshots {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                println!("could not snapshot pending downloads: {:?}", e);
+                return vec![];
+            }
+        };
+
+        let mut ready = vec![];
+        for (path, snap) in snapshots {
+            let Some(pending) = self.pending.get_mut(&path) else {
+                continue;
+            };
+            let watch = &self.config.watch_paths()[pending.watch];
+            match snap {
+                Err(_) => {
+                    self.units.forget(&path);
+                    self.pending.remove(&path);
+                }
+                Ok(snap) if !snap.in_progress && pending.snapshot == Some(snap) => {
+                    if self.units.transition(&path, UnitState::Importing) {
+                        ready.push((path.clone(), watch.clone()))
+                    }
+                    self.pending.remove(&path);
+                }
+                Ok(snap) => {
+                    self.units.transition(&path, UnitState::Stabilising);
+                    pending.snapshot = Some(snap);
+                    pending.last_change = Instant::now();
+                }
+            }
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, ScratchDir};
+    use serde_json::json;
+
+    #[test]
+    fn unit_paths_sit_depth_levels_below_the_watch_path() {
+        let config = config(json!({
+            "watch": [
+                { "path": "/srv/done" },
+                { "path": "/srv/sorted", "depth": 2 },
+            ],
+        }));
+        let [flat, nested] = config.watch_paths() else {
+            panic!("expected two watch paths")
+        };
+        assert_eq!(
+            unit_path(flat, Path::new("/srv/done/Show.S01/e01.mkv")),
+            Some(PathBuf::from("/srv/done/Show.S01"))
+        );
+        assert_eq!(
+            unit_path(nested, Path::new("/srv/sorted/tv/Show.S01/e01.mkv")),
+            Some(PathBuf::from("/srv/sorted/tv/Show.S01"))
+        );
+        assert_eq!(unit_path(nested, Path::new("/srv/sorted/tv")), None);
+        assert_eq!(unit_path(flat, Path::new("/srv/elsewhere/x.mkv")), None);
+        assert_eq!(unit_path(flat, Path::new("/srv/done/../x.mkv")), None);
+    }
+
+    #[test]
+    fn events_go_to_the_deepest_watch_path_holding_them() {
+        let config = config(json!({
+            "watch": ["/srv/done", "/srv/done/manual", "/srv/usenet"],
+        }));
+        let stabiliser = Stabiliser::new(Arc::new(config), Units::default());
+        assert_eq!(
+            stabiliser.locate(Path::new("/srv/done/manual/Movie.2020/a.mkv")),
+            Some((1, PathBuf::from("/srv/done/manual/Movie.2020")))
+        );
+        assert_eq!(
+            stabiliser.locate(Path::new("/srv/done/Show.S01E01.mkv")),
+            Some((0, PathBuf::from("/srv/done/Show.S01E01.mkv")))
+        );
+        assert_eq!(
+            stabiliser.locate(Path::new("/srv/usenet/x.mkv")),
+            Some((2, PathBuf::from("/srv/usenet/x.mkv")))
+        );
+        assert_eq!(stabiliser.locate(Path::new("/srv/elsewhere/x.mkv")), None);
+    }
+
+    #[test]
+    fn snapshots_cover_everything_below_an_entry() {
+        let dir = ScratchDir::new("stabilise");
+        let stabilise = StabiliseConfig::default();
+        fs::create_dir_all(dir.join("Show.S01/extras")).unwrap();
+        fs::write(dir.join("Show.S01/e01.mkv"), [0; 10]).unwrap();
+        fs::write(dir.join("Show.S01/extras/e02.mkv"), [0; 5]).unwrap();
+        let before = snapshot(&dir.join("Show.S01"), &stabilise).unwrap();
+        assert!(!before.in_progress);
+
+        fs::write(dir.join("Show.S01/extras/e02.mkv"), [0; 6]).unwrap();
+        let after = snapshot(&dir.join("Show.S01"), &stabilise).unwrap();
+        assert_eq!(after.size, before.size + 1);
+
+        fs::write(dir.join("Show.S01/extras/e03.mkv.part"), [0; 1]).unwrap();
+        assert!(
+            snapshot(&dir.join("Show.S01"), &stabilise)
+                .unwrap()
+                .in_progress
+        );
+    }
+
+    #[tokio::test]
+    async fn downloads_are_released_once_they_stop_changing() {
+        let dir = ScratchDir::new("stabilise-flow");
+        let show = dir.join("Show.S01");
+        fs::create_dir_all(&show).unwrap();
+        fs::write(show.join("e01.mkv.part"), [0; 10]).unwrap();
+        let config = config(json!({
+            "watch": [{ "path": dir.path(), "stabilise": { "quietSecs": 0 } }],
+        }));
+        let units = Units::default();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut ready = Stabiliser::new(Arc::new(config), units.clone()).spawn(rx);
+
+        tx.send(WatchEvent::Changed(vec![show.join("e01.mkv.part")]))
+            .unwrap();
+        let early = tokio::time::timeout(Duration::from_secs(1), ready.recv()).await;
+        assert!(early.is_err(), "released while still downloading");
+        assert_eq!(units.state(&show), Some(UnitState::Stabilising));
+
+        fs::rename(show.join("e01.mkv.part"), show.join("e01.mkv")).unwrap();
+        tx.send(WatchEvent::Changed(vec![show.join("e01.mkv")]))
+            .unwrap();
+        let (path, watch) = tokio::time::timeout(Duration::from_secs(5), ready.recv())
+            .await
+            .expect("never released")
+            .unwrap();
+        assert_eq!(path, show);
+        assert_eq!(watch.path, dir.path());
+        assert_eq!(units.state(&show), Some(UnitState::Importing));
+    }
+}
@@ -0,0 +1,551 @@
+use crate::config::atomic::write_atomic;
+use anyhow::{bail, Ok as AnyOk, Result as AnyResult};
+use serde::{Deserialize, Serialize};
+use serde_json::to_vec_pretty;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Wait before the first retry of a failed unit, doubled on every failure.
+const RETRY_BASE: Duration = Duration::from_secs(60);
+/// Longest wait between two retries.
+const RETRY_CAP: Duration = Duration::from_secs(24 * 60 * 60);
+/// Failed units are left alone after this many attempts.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Lifecycle of one top level entry in the watch path.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum UnitState {
+    /// Seen an event, waiting for the first quiet period.
+    Pending,
+    /// Quiet once, waiting for a second identical snapshot.
+    Stabilising,
+    /// Handed to the mover.
+    Importing,
+    Done,
+    /// Import failed, retried with backoff.
+    Failed(String),
+    /// The name could not be parsed and the entry sits in quarantine until it
+    /// is reprocessed by hand, so it is never retried.
+    Quarantined(String),
+}
+
+impl UnitState {
+    fn can_become(&self, next: &UnitState) -> bool {
+        use UnitState::*;
+        matches!(
+            (self, next),
+            (Pending, Pending)
+                | (Pending, Stabilising)
+                | (Stabilising, Pending)
+                | (Stabilising, Stabilising)
+                | (Stabilising, Importing)
+                | (Importing, Done)
+                | (Importing, Failed(_))
+                | (Importing, Quarantined(_))
+        )
+    }
+
+    /// Finished units are remembered across restarts and only picked up again
+    /// once their entry is replaced.
+    pub fn is_settled(&self) -> bool {
+        matches!(
+            self,
+            UnitState::Done | UnitState::Failed(_) | UnitState::Quarantined(_)
+        )
+    }
+}
+
+/// Tells a replaced entry apart from the one that was handled under the same
+/// name: device and inode on unix, modification time elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    dev: u64,
+    ino: u64,
+}
+
+impl Identity {
+    #[cfg(unix)]
+    fn of(path: &Path) -> Option<Identity> {
+        use std::os::unix::fs::MetadataExt;
+        let meta = fs::symlink_metadata(path).ok()?;
+        Some(Identity {
+            dev: meta.dev(),
+            ino: meta.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn of(path: &Path) -> Option<Identity> {
+        let modified = fs::symlink_metadata(path).ok()?.modified().ok()?;
+        let since = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+        Some(Identity {
+            dev: 0,
+            ino: since.as_nanos() as u64,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Unit {
+    state: UnitState,
+    /// Recorded when the unit settles.
+    identity: Option<Identity>,
+    /// Failed imports so far, carried through retries.
+    failures: u32,
+    /// Seconds since the epoch of the last failure.
+    failed_at: u64,
+}
+
+impl Unit {
+    /// A settled unit whose entry has since been replaced, e.g. by a new
+    /// download under the same name, is treated as unknown again.
+    fn is_replaced(&self, path: &Path) -> bool {
+        self.state.is_settled()
+            && match Identity::of(path) {
+                Some(now) => self.identity != Some(now),
+                None => false,
+            }
+    }
+
+    /// When a failed unit should be tried again, or None if it should not.
+    fn retry_at(&self) -> Option<u64> {
+        if !matches!(self.state, UnitState::Failed(_)) || self.failures >= MAX_ATTEMPTS {
+            return None;
+        }
+        let wait = RETRY_BASE
+            .saturating_mul(1 << self.failures.saturating_sub(1).min(16))
+            .min(RETRY_CAP);
+        Some(self.failed_at + wait.as_secs())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+/// How a settled unit is written to the units file.
+#[derive(Serialize, Deserialize)]
+struct UnitRecord {
+    #[serde(with = "crate::config::raw_path")]
+    path: PathBuf,
+    state: UnitState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    identity: Option<Identity>,
+    #[serde(default)]
+    failures: u32,
+    #[serde(default)]
+    failed_at: u64,
+}
+
+/// Schema version of the units file this build writes.
+const VERSION: u64 = 3;
+
+/// Version 1 was a map keyed by path, which cannot hold non UTF-8 names, and
+/// version 2 a bare list of records.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UnitsFile {
+    Versioned {
+        version: u64,
+        units: Vec<UnitRecord>,
+    },
+    Records(Vec<UnitRecord>),
+    Map(HashMap<PathBuf, UnitState>),
+}
+
+#[derive(Serialize)]
+struct UnitsDocument<'a> {
+    version: u64,
+    units: &'a [UnitRecord],
+}
+
+/// Shared table of unit states, so the stabiliser and the mover agree on
+/// what has already been handled. Settled states are written to `file`.
+#[derive(Debug, Clone, Default)]
+pub struct Units {
+    file: Option<PathBuf>,
+    states: Arc<Mutex<HashMap<PathBuf, Unit>>>,
+}
+
+impl Units {
+    pub fn load(file: PathBuf) -> AnyResult<Units> {
+        let records = if file.is_file() {
+            match serde_json::from_slice(&fs::read(&file)?)? {
+                UnitsFile::Map(states) => states
+                    .into_iter()
+                    .map(|(path, state)| UnitRecord {
+                        path,
+                        state,
+                        identity: None,
+                        failures: 0,
+                        failed_at: 0,
+                    })
+                    .collect(),
+                UnitsFile::Records(records) => records,
+                UnitsFile::Versioned { version, .. } if version > VERSION => bail!(
+                    "{:?} has schema version {}, newer than the supported {}",
+                    file,
+                    version,
+                    VERSION
+                ),
+                UnitsFile::Versioned { units, .. } => units,
+            }
+        } else {
+            vec![]
+        };
+        let states = records
+            .into_iter()
+            .map(|record| {
+                // Files written before identities were recorded describe
+                // whatever is on disk now.
+                let identity = record.identity.or_else(|| Identity::of(&record.path));
+                let unit = Unit {
+                    state: record.state,
+                    identity,
+                    failures: record.failures,
+                    failed_at: record.failed_at,
+                };
+                (record.path, unit)
+            })
+            .collect();
+        let units = Self {
+            file: Some(file),
+            states: Arc::new(Mutex::new(states)),
+        };
+        units.prune_missing();
+        AnyOk(units)
+    }
+
+    #[cfg(test)]
+    pub fn state(&self, path: &Path) -> Option<UnitState> {
+        self.lock().get(path).map(|unit| unit.state.clone())
+    }
+
+    /// Whether `path` was already imported or failed, and has not been
+    /// replaced since.
+    pub fn is_handled(&self, path: &Path) -> bool {
+        matches!(self.lock().get(path), Some(unit) if unit.state.is_settled() && !unit.is_replaced(path))
+    }
+
+    /// Moves a unit to `next`, returning false if that transition is not allowed.
+    /// Unknown and replaced units may only start as `Pending`.
+    pub fn transition(&self, path: &Path, next: UnitState) -> bool {
+        let mut states = self.lock();
+        let current = states.get(path).filter(|unit| !unit.is_replaced(path));
+        let allowed = match current {
+            Some(unit) => unit.state.can_become(&next),
+            None => next == UnitState::Pending,
+        };
+        if allowed {
+            let settled = next.is_settled();
+            let identity = if settled { Identity::of(path) } else { None };
+            let mut failures = current.map(|unit| unit.failures).unwrap_or_default();
+            let mut failed_at = current.map(|unit| unit.failed_at).unwrap_or_default();
+            if let UnitState::Failed(_) = next {
+                failures += 1;
+                failed_at = now();
+            }
+            states.insert(
+                path.to_path_buf(),
+                Unit {
+                    state: next,
+                    identity,
+                    failures,
+                    failed_at,
+                },
+            );
+            if settled {
+                if let Err(e) = self.save(&states) {
+                    println!("could not save unit states: {:?}", e)
+                }
+            }
+        }
+        allowed
+    }
+
+    fn save(&self, states: &HashMap<PathBuf, Unit>) -> AnyResult<()> {
+        if let Some(file) = &self.file {
+            let settled: Vec<UnitRecord> = states
+                .iter()
+                .filter(|(_, unit)| unit.state.is_settled())
+                .map(|(path, unit)| UnitRecord {
+                    path: path.clone(),
+                    state: unit.state.clone(),
+                    identity: unit.identity,
+                    failures: unit.failures,
+                    failed_at: unit.failed_at,
+                })
+                .collect();
+            let document = UnitsDocument {
+                version: VERSION,
+                units: &settled,
+            };
+            write_atomic(file, &to_vec_pretty(&document)?)?;
+        }
+        AnyOk(())
+    }
+
+    /// Drops a unit that disappeared before it was imported.
+    pub fn forget(&self, path: &Path) {
+        let mut states = self.lock();
+        if let Some(Unit {
+            state: UnitState::Pending | UnitState::Stabilising,
+            ..
+        }) = states.get(path)
+        {
+            states.remove(path);
+        }
+    }
+
+    /// Drops settled units whose entry has left the watch path, so the table
+    /// only holds what is still on disk and a later download under the same
+    /// name is imported.
+    pub fn prune_missing(&self) {
+        let mut states = self.lock();
+        let before = states.len();
+        states.retain(|path, unit| !unit.state.is_settled() || fs::symlink_metadata(path).is_ok());
+        if states.len() != before {
+            if let Err(e) = self.save(&states) {
+                println!("could not save unit states: {:?}", e)
+            }
+        }
+    }
+
+    /// Puts failed units whose backoff has run out back to `Pending` and
+    /// returns them, so they go through the import pipeline again.
+    pub fn take_due_retries(&self) -> Vec<PathBuf> {
+        self.due_retries_at(now())
+    }
+
+    fn due_retries_at(&self, now: u64) -> Vec<PathBuf> {
+        let mut states = self.lock();
+        let due: Vec<PathBuf> = states
+            .iter()
+            .filter(|(_, unit)| matches!(unit.retry_at(), Some(at) if at <= now))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &due {
+            if let Some(unit) = states.get_mut(path) {
+                println!("retrying {:?} after {} failures", path, unit.failures);
+                unit.state = UnitState::Pending;
+                unit.identity = None;
+            }
+        }
+        due
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Unit>> {
+        self.states.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    fn import(units: &Units, path: &Path, outcome: UnitState) {
+        for state in [
+            UnitState::Pending,
+            UnitState::Stabilising,
+            UnitState::Importing,
+            outcome,
+        ] {
+            assert!(units.transition(path, state.clone()), "{:?}", state);
+        }
+    }
+
+    #[test]
+    fn units_only_move_forward() {
+        let units = Units::default();
+        let path = Path::new("/srv/done/Show.S01E01.mkv");
+        assert!(!units.transition(path, UnitState::Importing));
+        assert!(units.transition(path, UnitState::Pending));
+        assert!(!units.transition(path, UnitState::Done));
+        assert!(units.transition(path, UnitState::Stabilising));
+        assert!(units.transition(path, UnitState::Pending));
+        assert!(units.transition(path, UnitState::Stabilising));
+        assert!(units.transition(path, UnitState::Importing));
+        assert!(!units.transition(path, UnitState::Pending));
+        assert!(units.transition(path, UnitState::Failed("oops".to_string())));
+        assert!(!units.transition(path, UnitState::Done));
+    }
+
+    #[test]
+    fn forget_keeps_units_that_reached_the_mover() {
+        let units = Units::default();
+        let waiting = Path::new("/srv/done/a.mkv");
+        let moving = Path::new("/srv/done/b.mkv");
+        units.transition(waiting, UnitState::Pending);
+        units.transition(moving, UnitState::Pending);
+        units.transition(moving, UnitState::Stabilising);
+        units.transition(moving, UnitState::Importing);
+        units.forget(waiting);
+        units.forget(moving);
+        assert_eq!(units.state(waiting), None);
+        assert_eq!(units.state(moving), Some(UnitState::Importing));
+    }
+
+    #[test]
+    fn settled_units_survive_a_restart() {
+        let dir = ScratchDir::new("units-restart");
+        let entry = dir.join("Show.S01E01.mkv");
+        fs::write(&entry, "video").unwrap();
+        let units = Units::load(dir.join("units.json")).unwrap();
+        import(&units, &entry, UnitState::Done);
+
+        let units = Units::load(dir.join("units.json")).unwrap();
+        assert_eq!(units.state(&entry), Some(UnitState::Done));
+        assert!(units.is_handled(&entry));
+        assert!(!units.transition(&entry, UnitState::Pending));
+    }
+
+    #[test]
+    fn a_replaced_entry_is_imported_again() {
+        let dir = ScratchDir::new("units-replaced");
+        let entry = dir.join("Show.S01E01.mkv");
+        fs::write(&entry, "video").unwrap();
+        let units = Units::load(dir.join("units.json")).unwrap();
+        import(&units, &entry, UnitState::Done);
+
+        // Keep the old inode alive so the new file cannot reuse its number.
+        fs::rename(&entry, dir.join("old.mkv")).unwrap();
+        fs::write(&entry, "proper").unwrap();
+        assert!(!units.is_handled(&entry));
+        import(&units, &entry, UnitState::Done);
+        assert!(units.is_handled(&entry));
+    }
+
+    #[test]
+    fn units_that_left_the_watch_path_are_pruned() {
+        let dir = ScratchDir::new("units-prune");
+        let moved = dir.join("moved.mkv");
+        let kept = dir.join("kept.mkv");
+        fs::write(&moved, "video").unwrap();
+        fs::write(&kept, "video").unwrap();
+        let units = Units::load(dir.join("units.json")).unwrap();
+        import(&units, &moved, UnitState::Done);
+        import(&units, &kept, UnitState::Failed("no match".to_string()));
+
+        fs::remove_file(&moved).unwrap();
+        units.prune_missing();
+        assert_eq!(units.state(&moved), None);
+        assert!(units.is_handled(&kept));
+
+        let units = Units::load(dir.join("units.json")).unwrap();
+        assert_eq!(units.state(&moved), None);
+        assert!(units.is_handled(&kept));
+    }
+
+    #[test]
+    fn failed_units_are_retried_with_backoff() {
+        let units = Units::default();
+        let path = Path::new("/srv/done/Show.S01E01.mkv");
+        import(&units, path, UnitState::Failed("disk full".to_string()));
+        let failed_at = now();
+
+        assert!(units.due_retries_at(failed_at + 59).is_empty());
+        assert_eq!(
+            units.due_retries_at(failed_at + 60),
+            vec![path.to_path_buf()]
+        );
+        assert_eq!(units.state(path), Some(UnitState::Pending));
+
+        for state in [UnitState::Stabilising, UnitState::Importing] {
+            assert!(units.transition(path, state));
+        }
+        assert!(units.transition(path, UnitState::Failed("disk full".to_string())));
+        let failed_at = now();
+        assert!(units.due_retries_at(failed_at + 60).is_empty());
+        assert_eq!(units.due_retries_at(failed_at + 120).len(), 1);
+    }
+
+    #[test]
+    fn retries_stop_after_the_last_attempt() {
+        let units = Units::default();
+        let path = Path::new("/srv/done/Show.S01E01.mkv");
+        import(&units, path, UnitState::Failed("disk full".to_string()));
+        for _ in 1..MAX_ATTEMPTS {
+            assert_eq!(units.due_retries_at(u64::MAX).len(), 1);
+            for state in [UnitState::Stabilising, UnitState::Importing] {
+                assert!(units.transition(path, state));
+            }
+            assert!(units.transition(path, UnitState::Failed("disk full".to_string())));
+        }
+        assert!(units.due_retries_at(u64::MAX).is_empty());
+        assert!(units.is_handled(path));
+    }
+
+    #[test]
+    fn quarantined_units_are_not_retried() {
+        let units = Units::default();
+        let path = Path::new("/srv/done/Unknown.mkv");
+        import(
+            &units,
+            path,
+            UnitState::Quarantined("no episode".to_string()),
+        );
+        assert!(units.due_retries_at(u64::MAX).is_empty());
+        assert!(units.is_handled(path));
+    }
+
+    #[test]
+    fn retry_counts_survive_a_restart() {
+        let dir = ScratchDir::new("units-retry-restart");
+        let entry = dir.join("Show.S01E01.mkv");
+        fs::write(&entry, "video").unwrap();
+        let units = Units::load(dir.join("units.json")).unwrap();
+        import(&units, &entry, UnitState::Failed("disk full".to_string()));
+        let failed_at = now();
+
+        let units = Units::load(dir.join("units.json")).unwrap();
+        assert!(units.due_retries_at(failed_at + 59).is_empty());
+        assert_eq!(units.due_retries_at(failed_at + 60), vec![entry]);
+    }
+
+    #[test]
+    fn legacy_maps_are_read_as_handled() {
+        let dir = ScratchDir::new("units-legacy");
+        let entry = dir.join("Show.S01E01.mkv");
+        fs::write(&entry, "video").unwrap();
+        let file = dir.join("units.json");
+        let legacy = serde_json::json!({ entry.to_str().unwrap(): "Done" });
+        fs::write(&file, legacy.to_string()).unwrap();
+
+        let units = Units::load(file).unwrap();
+        assert!(units.is_handled(&entry));
+    }
+
+    #[test]
+    fn unversioned_record_lists_are_read() {
+        let dir = ScratchDir::new("units-v2");
+        let entry = dir.join("Show.S01E01.mkv");
+        fs::write(&entry, "video").unwrap();
+        let file = dir.join("units.json");
+        let records = serde_json::json!([{ "path": entry, "state": "Done" }]);
+        fs::write(&file, records.to_string()).unwrap();
+
+        let units = Units::load(file.clone()).unwrap();
+        assert!(units.is_handled(&entry));
+        let next = dir.join("Show.S01E02.mkv");
+        fs::write(&next, "video").unwrap();
+        import(&units, &next, UnitState::Done);
+        let saved: serde_json::Value = serde_json::from_slice(&fs::read(&file).unwrap()).unwrap();
+        assert_eq!(saved["version"], VERSION);
+    }
+
+    #[test]
+    fn units_from_a_newer_build_are_refused() {
+        let dir = ScratchDir::new("units-newer");
+        let file = dir.join("units.json");
+        let newer = serde_json::json!({ "version": VERSION + 1, "units": [] });
+        fs::write(&file, newer.to_string()).unwrap();
+        assert!(Units::load(file).is_err());
+    }
+}